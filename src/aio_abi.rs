@@ -91,6 +91,15 @@ pub fn io_prep_pread(iocb: &mut iocb, fd: int32_t, buf: *mut c_void, count: uint
     iocb.offset = offset;
 }
 
+pub fn io_prep_pwrite(iocb: &mut iocb, fd: int32_t, buf: *mut c_void, count: uint64_t, offset: int64_t) {
+    iocb.fildes = cast::<int32_t, uint32_t>(fd).unwrap();
+    iocb.lio_opcode = iocb_cmd::IOCB_CMD_PWRITE as u16;
+    iocb.reqprio = 0;
+    iocb.buf = buf as u64;
+    iocb.nbytes = count;
+    iocb.offset = offset;
+}
+
 #[link(name = "aio")]
 extern "C" {
     pub fn io_setup(maxevents: c_int, ctxp: *mut aio_context_t) -> c_int;
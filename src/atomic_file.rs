@@ -0,0 +1,61 @@
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+pub const TMP_SUFFIX: &'static str = "ddarescue-tmp";
+pub const BACKUP_SUFFIX: &'static str = "bak";
+
+// Returns the path of a sibling generation of `path`, e.g. `drive.map` -> `drive.map.bak`.
+pub fn generation_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+// Replaces the contents of `path` such that a crash at any point leaves either the old or the new
+// contents in place. Any temporary left over from an earlier crash is overwritten. If
+// `keep_backup` is set, the previous contents are retained as the backup generation.
+pub fn replace<F>(path: &Path, keep_backup: bool, write: F) -> io::Result<()>
+    where F: FnOnce(&mut File) -> io::Result<()> {
+    let tmp_path = generation_path(path, TMP_SUFFIX);
+    {
+        let mut file = OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .open(&tmp_path)?;
+        write(&mut file)?;
+        file.flush()?;
+        file.sync_all()?;
+    }
+    if keep_backup && path.exists() {
+        replace_backup(path, &generation_path(path, BACKUP_SUFFIX))?;
+    }
+    fs::rename(&tmp_path, path)?;
+    sync_parent_dir(path)
+}
+
+fn replace_backup(path: &Path, backup_path: &Path) -> io::Result<()> {
+    match fs::remove_file(backup_path) {
+        Ok(()) => {},
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+        Err(e) => return Err(e),
+    }
+    // A hard link avoids copying, but not every filesystem supports them. A copy is newer than
+    // `path`, so readers must not pick a generation by its modification time.
+    if fs::hard_link(path, backup_path).is_err() {
+        fs::copy(path, backup_path)?;
+        File::open(backup_path)?.sync_all()?;
+    }
+    Ok(())
+}
+
+pub fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()
+}
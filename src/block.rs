@@ -1,7 +1,9 @@
 use aio_abi::{self, aio_context_t, io_event, iocb};
+use input::Input;
 use libc::{self, c_int, c_uint, c_void};
 use nix;
 use num::cast;
+use std::cmp;
 use std::collections::BTreeMap;
 use std::error::Error;
 use std::ptr;
@@ -9,6 +11,7 @@ use std::slice;
 use std::fs::{File, OpenOptions};
 use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
 
 const MAX_EVENTS: usize = 32;
 
@@ -34,6 +37,8 @@ pub struct BlockDevice {
     file: File,
     iocbs: Vec<(bool, iocb)>,
     requests: BTreeMap<usize, Request>,
+    // When the last read completed, which is when the device can start on the next one
+    last_read_completed: Option<Instant>,
     sector_size: usize,
     size_bytes: u64,
 }
@@ -46,12 +51,24 @@ mod ioctl {
     pub const PBSZGET: c_uint = 123;
 }
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Operation {
+    Read,
+    Write,
+}
+
 #[derive(Debug)]
 pub struct Request {
     pub offset: u64,
     pub size: u64,
     pub buffer: Buffer,
     pub result: isize,
+    pub operation: Operation,
+    // When the request was last submitted, when the device could start on it (once the reads
+    // queued before it had completed) and when it completed
+    pub submitted: Option<Instant>,
+    pub started: Option<Instant>,
+    pub completed: Option<Instant>,
 }
 
 impl Request {
@@ -62,6 +79,10 @@ impl Request {
             size: size,
             buffer: buffer,
             result: -1,
+            operation: Operation::Read,
+            submitted: None,
+            started: None,
+            completed: None,
         }
     }
 
@@ -73,6 +94,15 @@ impl Request {
         }
     }
 
+    // The time the device took to complete the request, not counting the time it spent queued
+    // behind other reads
+    pub fn get_latency(&self) -> Option<Duration> {
+        match (self.started, self.completed) {
+            (Some(started), Some(completed)) => Some(completed.duration_since(started)),
+            _ => None,
+        }
+    }
+
     pub fn reclaim_buffer(self) -> Buffer {
         self.buffer
     }
@@ -118,6 +148,13 @@ impl Buffer {
         }
     }
 
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe {
+            let data = self.data as *mut u8;
+            slice::from_raw_parts_mut(data, self.size)
+        }
+    }
+
     pub fn clear(&mut self) {
         unsafe {
             libc::memset(self.data, 0, self.size);
@@ -156,6 +193,7 @@ impl BlockDevice {
             file: file,
             iocbs: iocbs,
             requests: BTreeMap::new(),
+            last_read_completed: None,
             size_bytes: size_bytes,
             sector_size: cast::<u32, usize>(sector_size).unwrap(),
         };
@@ -166,7 +204,7 @@ impl BlockDevice {
         self.file.as_raw_fd()
     }
 
-    fn query_block_size_physical(fd: c_int) -> Result<c_uint, nix::Error> {
+    pub fn query_block_size_physical(fd: c_int) -> Result<c_uint, nix::Error> {
         let mut block_size_physical: c_uint = 0;
         let ioc = ioc!(nix::sys::ioctl::NONE, ioctl::BLK, ioctl::PBSZGET, 0);
         if unsafe { libc::ioctl(fd, ioc, &mut block_size_physical as *mut c_uint) } == -1 {
@@ -176,7 +214,7 @@ impl BlockDevice {
         }
     }
 
-    fn query_sector_size(fd: c_int) -> Result<c_uint, nix::Error> {
+    pub fn query_sector_size(fd: c_int) -> Result<c_uint, nix::Error> {
         let mut sector_size: c_uint = 0;
         let ioc = ioc!(nix::sys::ioctl::NONE, ioctl::BLK, ioctl::SSZGET, 0);
         if unsafe { libc::ioctl(fd, ioc, &mut sector_size as *mut c_uint) } == -1 {
@@ -186,7 +224,7 @@ impl BlockDevice {
         }
     }
 
-    fn query_size_bytes(fd: c_int) -> Result<u64, nix::Error> {
+    pub fn query_size_bytes(fd: c_int) -> Result<u64, nix::Error> {
         let mut size_bytes: u64 = 0;
         let ioc = ior!(ioctl::BLK, ioctl::GETSIZE64, 8);
         if unsafe { libc::ioctl(fd, ioc, &mut size_bytes as *mut u64) } == -1 {
@@ -197,12 +235,30 @@ impl BlockDevice {
     }
 
     pub fn submit_request(&mut self, req: Request) -> Result<(), nix::Error> {
-        assert!(self.requests_avail() > 0);
         let fd = self.get_fd();
+        self.submit(fd, req)
+    }
+
+    // Writes the data read by a completed request to `fd` at the same offset. The buffer is
+    // returned with the request once the write completes.
+    pub fn submit_write(&mut self, fd: c_int, mut req: Request) -> Result<(), nix::Error> {
+        assert!(req.result >= 0, "Cannot write data from a failed read");
+        req.size = cast::<isize, u64>(req.result).unwrap();
+        req.result = -1;
+        req.operation = Operation::Write;
+        self.submit(fd, req)
+    }
+
+    fn submit(&mut self, fd: c_int, mut req: Request) -> Result<(), nix::Error> {
+        assert!(self.requests_avail() > 0);
         let slot = self.find_slot();
         let iocb = &mut self.iocbs[slot];
         iocb.0 = true;
-        aio_abi::io_prep_pread(&mut iocb.1, fd, req.buffer.data, req.size, cast::<u64, i64>(req.offset).unwrap());
+        let offset = cast::<u64, i64>(req.offset).unwrap();
+        match req.operation {
+            Operation::Read => aio_abi::io_prep_pread(&mut iocb.1, fd, req.buffer.data, req.size, offset),
+            Operation::Write => aio_abi::io_prep_pwrite(&mut iocb.1, fd, req.buffer.data, req.size, offset),
+        }
         iocb.1.data = cast::<usize, u64>(slot).unwrap();
         let iocb_ptr = &mut iocb.1 as *mut iocb;
         let mut iocb_list = [iocb_ptr];
@@ -210,9 +266,13 @@ impl BlockDevice {
             aio_abi::io_submit(self.context, cast::<usize, i64>(iocb_list.len()).unwrap(), &mut iocb_list[0] as *mut *mut iocb)
         };
         if res < 0 {
+            self.iocbs[slot].0 = false;
             let errno = nix::Errno::from_i32(-res);
             Err(nix::Error::Sys(errno))
         } else {
+            req.submitted = Some(Instant::now());
+            req.started = None;
+            req.completed = None;
             self.requests.insert(slot, req);
             Ok(())
         }
@@ -233,6 +293,13 @@ impl BlockDevice {
             *used = false;
             let mut req = self.requests.remove(&slot).unwrap();
             req.result = cast::<i64, isize>(event.res).unwrap();
+            let now = Instant::now();
+            req.completed = Some(now);
+            req.started = req.submitted;
+            if req.operation == Operation::Read {
+                req.started = cmp::max(req.submitted, self.last_read_completed);
+                self.last_read_completed = Some(now);
+            }
             return Ok(req);
         }
     }
@@ -274,12 +341,66 @@ impl BlockDevice {
         self.iocbs.iter().filter(|r| r.0).count()
     }
 
+    // The offset of the furthest read in flight, which is in the area being read
+    pub fn last_pending_read(&self) -> Option<u64> {
+        self.requests.values()
+            .filter(|req| req.operation == Operation::Read)
+            .map(|req| req.offset)
+            .max()
+    }
+
     pub fn create_io_buffer(&self, sectors: usize) -> Buffer {
         let bytes = sectors * self.sector_size;
         Buffer::allocate_aligned(bytes, self.sector_size)
     }
 }
 
+impl Input for BlockDevice {
+    fn submit_request(&mut self, req: Request) -> Result<(), nix::Error> {
+        BlockDevice::submit_request(self, req)
+    }
+
+    fn submit_write(&mut self, fd: c_int, req: Request) -> Result<(), nix::Error> {
+        BlockDevice::submit_write(self, fd, req)
+    }
+
+    fn get_completed_request(&mut self) -> Result<Request, nix::Error> {
+        BlockDevice::get_completed_request(self)
+    }
+
+    fn last_pending_read(&self) -> Option<u64> {
+        BlockDevice::last_pending_read(self)
+    }
+
+    fn max_requests(&self) -> usize {
+        BlockDevice::max_requests(self)
+    }
+
+    fn requests_avail(&self) -> usize {
+        BlockDevice::requests_avail(self)
+    }
+
+    fn requests_pending(&self) -> usize {
+        BlockDevice::requests_pending(self)
+    }
+
+    fn get_block_size_physical(&self) -> usize {
+        BlockDevice::get_block_size_physical(self)
+    }
+
+    fn get_sector_size(&self) -> usize {
+        BlockDevice::get_sector_size(self)
+    }
+
+    fn get_size_bytes(&self) -> u64 {
+        BlockDevice::get_size_bytes(self)
+    }
+
+    fn create_io_buffer(&self, sectors: usize) -> Buffer {
+        BlockDevice::create_io_buffer(self, sectors)
+    }
+}
+
 impl Drop for BlockDevice {
     fn drop(&mut self) {
         unsafe {
@@ -0,0 +1,752 @@
+use error::MismatchError;
+use lz4;
+use sink::Sink;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::ops::Range;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+// A seekable compressed drive image. The image is divided into fixed-size chunks, each of which is
+// compressed independently and stored in an extent of the file. An index following the header
+// maps each chunk to its most recent extent. Chunks consisting entirely of zeros have no extent.
+//
+// Extents referred to by the index on disk are never overwritten, and the index is only updated
+// once the extents it refers to have been synced, so a crash leaves the image as it was at the
+// last sync. An extent which has been superseded is reused for later ones once the index on disk
+// no longer refers to it. Otherwise extents are appended to the end of the file.
+//
+// The image may also carry a ddrescue map describing the rescue status. Two map extents are used
+// alternately so that a crash while embedding a map leaves the previous one intact.
+
+const MAGIC: &'static [u8; 8] = b"DDACIMG\0";
+const VERSION: u32 = 1;
+const HEADER_SIZE: u64 = 4096;
+const INDEX_ENTRY_SIZE: u64 = 16;
+const MAX_CACHED_CHUNKS: usize = 64;
+const MIN_MAP_CAPACITY: u64 = 4096;
+pub const DEFAULT_CHUNK_SIZE: u32 = 1 << 20;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u32)]
+enum Encoding {
+    Zero = 0,
+    Lz4 = 1,
+    Stored = 2,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct IndexEntry {
+    offset: u64,
+    length: u32,
+    encoding: Encoding,
+}
+
+impl IndexEntry {
+    fn zero() -> IndexEntry {
+        IndexEntry {
+            offset: 0,
+            length: 0,
+            encoding: Encoding::Zero,
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; INDEX_ENTRY_SIZE as usize] {
+        let mut bytes = [0u8; INDEX_ENTRY_SIZE as usize];
+        put_u64(&mut bytes[0..8], self.offset);
+        put_u32(&mut bytes[8..12], self.length);
+        put_u32(&mut bytes[12..16], self.encoding as u32);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> io::Result<IndexEntry> {
+        let encoding = match get_u32(&bytes[12..16]) {
+            0 => Encoding::Zero,
+            1 => Encoding::Lz4,
+            2 => Encoding::Stored,
+            _ => return Err(invalid_data("Unknown chunk encoding in compressed image index")),
+        };
+        Ok(IndexEntry {
+            offset: get_u64(&bytes[0..8]),
+            length: get_u32(&bytes[8..12]),
+            encoding: encoding,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct MapExtent {
+    offset: u64,
+    length: u64,
+    capacity: u64,
+}
+
+#[derive(Debug)]
+pub struct CompressedImage {
+    file: File,
+    size_bytes: u64,
+    chunk_size: u32,
+    index: Vec<IndexEntry>,
+    dirty_entries: BTreeSet<u64>,
+    // Chunks which have been written to since they were last flushed, in least recently used order
+    cache: HashMap<u64, Vec<u8>>,
+    lru: VecDeque<u64>,
+    end: u64,
+    // Unused extents which may be overwritten, by offset, and those superseded since the last sync,
+    // which may not be until the index on disk no longer refers to them
+    free_extents: BTreeMap<u64, u64>,
+    superseded_extents: Vec<(u64, u64)>,
+    map_extent: Option<MapExtent>,
+    spare_map_extent: Option<MapExtent>,
+}
+
+impl CompressedImage {
+    pub fn is_compressed_image(path: &Path) -> io::Result<bool> {
+        let mut magic = [0u8; 8];
+        let mut file = File::open(path)?;
+        match file.read_exact(&mut magic) {
+            Ok(()) => Ok(&magic == MAGIC),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn open(path: &Path, size_bytes: u64) -> io::Result<CompressedImage> {
+        if path.exists() {
+            let image = Self::open_existing(path)?;
+            if image.size_bytes != size_bytes {
+                return Err(MismatchError::new("Compressed image size does not match required length").into_io_error());
+            }
+            Ok(image)
+        } else {
+            Self::create(path, size_bytes, DEFAULT_CHUNK_SIZE)
+        }
+    }
+
+    pub fn create(path: &Path, size_bytes: u64, chunk_size: u32) -> io::Result<CompressedImage> {
+        let mut file = OpenOptions::new()
+            .create_new(true)
+            .read(true)
+            .write(true)
+            .open(path)?;
+        let chunk_count = (size_bytes + chunk_size as u64 - 1) / chunk_size as u64;
+        let mut header = vec![0u8; HEADER_SIZE as usize];
+        header[0..8].copy_from_slice(MAGIC);
+        put_u32(&mut header[8..12], VERSION);
+        put_u32(&mut header[12..16], chunk_size);
+        put_u64(&mut header[16..24], size_bytes);
+        // Bytes 24..40 hold the offset and length of the embedded map, which is initially absent
+        file.write_all(&header)?;
+        // A zero-filled index entry describes a zero chunk
+        let end = HEADER_SIZE + chunk_count * INDEX_ENTRY_SIZE;
+        file.set_len(end)?;
+        file.sync_all()?;
+        Ok(CompressedImage {
+            file: file,
+            size_bytes: size_bytes,
+            chunk_size: chunk_size,
+            index: vec![IndexEntry::zero(); chunk_count as usize],
+            dirty_entries: BTreeSet::new(),
+            cache: HashMap::new(),
+            lru: VecDeque::new(),
+            end: end,
+            free_extents: BTreeMap::new(),
+            superseded_extents: Vec::new(),
+            map_extent: None,
+            spare_map_extent: None,
+        })
+    }
+
+    pub fn open_existing(path: &Path) -> io::Result<CompressedImage> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)?;
+        let mut header = vec![0u8; HEADER_SIZE as usize];
+        file.read_exact(&mut header)?;
+        if &header[0..8] != MAGIC {
+            return Err(invalid_data("Not a compressed image"));
+        }
+        if get_u32(&header[8..12]) != VERSION {
+            return Err(invalid_data("Unsupported compressed image version"));
+        }
+        let chunk_size = get_u32(&header[12..16]);
+        let size_bytes = get_u64(&header[16..24]);
+        if chunk_size == 0 {
+            return Err(invalid_data("Invalid chunk size in compressed image"));
+        }
+        let chunk_count = (size_bytes + chunk_size as u64 - 1) / chunk_size as u64;
+        let mut index_bytes = vec![0u8; (chunk_count * INDEX_ENTRY_SIZE) as usize];
+        file.read_exact(&mut index_bytes)?;
+        let mut index = Vec::with_capacity(chunk_count as usize);
+        for entry in index_bytes.chunks(INDEX_ENTRY_SIZE as usize) {
+            index.push(IndexEntry::from_bytes(entry)?);
+        }
+        let end = file.metadata()?.len();
+        let map_extent = match get_u64(&header[32..40]) {
+            0 => None,
+            length => Some(MapExtent {
+                offset: get_u64(&header[24..32]),
+                length: length,
+                capacity: length,
+            }),
+        };
+        let mut image = CompressedImage {
+            file: file,
+            size_bytes: size_bytes,
+            chunk_size: chunk_size,
+            index: index,
+            dirty_entries: BTreeSet::new(),
+            cache: HashMap::new(),
+            lru: VecDeque::new(),
+            end: end,
+            free_extents: BTreeMap::new(),
+            superseded_extents: Vec::new(),
+            map_extent: map_extent,
+            spare_map_extent: None,
+        };
+        image.find_free_extents(HEADER_SIZE + chunk_count * INDEX_ENTRY_SIZE);
+        Ok(image)
+    }
+
+    // Any space after `start` which is not used by a chunk or the embedded map is free, including
+    // extents superseded before the image was last closed
+    fn find_free_extents(&mut self, start: u64) {
+        let mut used: Vec<(u64, u64)> = self.index.iter()
+            .filter(|entry| entry.encoding != Encoding::Zero)
+            .map(|entry| (entry.offset, entry.length as u64))
+            .collect();
+        if let Some(extent) = self.map_extent {
+            used.push((extent.offset, extent.length));
+        }
+        used.sort();
+        let mut pos = start;
+        for (offset, length) in used {
+            if offset > pos {
+                self.free_extent(pos, offset - pos);
+            }
+            pos = cmp::max(pos, offset + length);
+        }
+        if self.end > pos {
+            self.free_extent(pos, self.end - pos);
+        }
+    }
+
+    // Finds space for an extent of `length` bytes, preferring the first free extent large enough
+    fn allocate_extent(&mut self, length: u64) -> u64 {
+        let found = self.free_extents.iter()
+            .find(|&(_, &free_length)| free_length >= length)
+            .map(|(&offset, &free_length)| (offset, free_length));
+        match found {
+            Some((offset, free_length)) => {
+                self.free_extents.remove(&offset);
+                if free_length > length {
+                    self.free_extents.insert(offset + length, free_length - length);
+                }
+                offset
+            },
+            None => {
+                let offset = self.end;
+                self.end += length;
+                offset
+            },
+        }
+    }
+
+    // Merges the extent with any free extents either side of it, so that larger chunks can reuse it
+    fn free_extent(&mut self, offset: u64, length: u64) {
+        let mut start = offset;
+        let mut end = offset + length;
+        let previous = self.free_extents.range(..offset).next_back().map(|(&o, &l)| (o, l));
+        if let Some((previous_offset, previous_length)) = previous {
+            if previous_offset + previous_length == offset {
+                self.free_extents.remove(&previous_offset);
+                start = previous_offset;
+            }
+        }
+        if let Some(next_length) = self.free_extents.remove(&end) {
+            end += next_length;
+        }
+        self.free_extents.insert(start, end - start);
+    }
+
+    pub fn get_size_bytes(&self) -> u64 {
+        self.size_bytes
+    }
+
+    pub fn get_allocated_bytes(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.blocks() * 512)
+    }
+
+    fn chunk_range(&self, chunk: u64) -> Range<u64> {
+        let start = chunk * self.chunk_size as u64;
+        start..cmp::min(start + self.chunk_size as u64, self.size_bytes)
+    }
+
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let end = offset + data.len() as u64;
+        assert!(end <= self.size_bytes, "Write beyond end of compressed image");
+        let mut pos = offset;
+        while pos < end {
+            let chunk = pos / self.chunk_size as u64;
+            let chunk_range = self.chunk_range(chunk);
+            let length = cmp::min(end, chunk_range.end) - pos;
+            let within = (pos - chunk_range.start) as usize;
+            let source = (pos - offset) as usize;
+            self.cached_chunk(chunk)?[within..(within + length as usize)]
+                .copy_from_slice(&data[source..(source + length as usize)]);
+            pos += length;
+        }
+        self.evict(MAX_CACHED_CHUNKS)
+    }
+
+    pub fn write_zeros(&mut self, range: Range<u64>) -> io::Result<()> {
+        assert!(range.end <= self.size_bytes, "Write beyond end of compressed image");
+        let mut pos = range.start;
+        while pos < range.end {
+            let chunk = pos / self.chunk_size as u64;
+            let chunk_range = self.chunk_range(chunk);
+            let end = cmp::min(range.end, chunk_range.end);
+            if pos == chunk_range.start && end == chunk_range.end {
+                self.cache.remove(&chunk);
+                self.lru.retain(|c| *c != chunk);
+                self.set_index_entry(chunk, IndexEntry::zero());
+            } else if self.cache.contains_key(&chunk) || self.index[chunk as usize].encoding != Encoding::Zero {
+                let within = (pos - chunk_range.start) as usize;
+                for value in &mut self.cached_chunk(chunk)?[within..(within + (end - pos) as usize)] {
+                    *value = 0;
+                }
+            }
+            pos = end;
+        }
+        self.evict(MAX_CACHED_CHUNKS)
+    }
+
+    pub fn read_at(&mut self, offset: u64, data: &mut [u8]) -> io::Result<()> {
+        let end = offset + data.len() as u64;
+        assert!(end <= self.size_bytes, "Read beyond end of compressed image");
+        let mut pos = offset;
+        while pos < end {
+            let chunk = pos / self.chunk_size as u64;
+            let chunk_range = self.chunk_range(chunk);
+            let length = (cmp::min(end, chunk_range.end) - pos) as usize;
+            let within = (pos - chunk_range.start) as usize;
+            let target = &mut data[((pos - offset) as usize)..][..length];
+            match self.cache.get(&chunk) {
+                Some(cached) => target.copy_from_slice(&cached[within..(within + length)]),
+                None => target.copy_from_slice(&self.read_chunk(chunk)?[within..(within + length)]),
+            }
+            pos += length as u64;
+        }
+        Ok(())
+    }
+
+    pub fn is_range_zero(&mut self, range: Range<u64>) -> io::Result<bool> {
+        let mut pos = range.start;
+        while pos < range.end {
+            let chunk = pos / self.chunk_size as u64;
+            let end = cmp::min(range.end, self.chunk_range(chunk).end);
+            if self.cache.contains_key(&chunk) || self.index[chunk as usize].encoding != Encoding::Zero {
+                let mut data = vec![0u8; (end - pos) as usize];
+                self.read_at(pos, &mut data)?;
+                if data.iter().any(|v| *v != 0) {
+                    return Ok(false);
+                }
+            }
+            pos = end;
+        }
+        Ok(true)
+    }
+
+    fn cached_chunk(&mut self, chunk: u64) -> io::Result<&mut Vec<u8>> {
+        if !self.cache.contains_key(&chunk) {
+            let data = self.read_chunk(chunk)?;
+            self.cache.insert(chunk, data);
+        }
+        if self.lru.back() != Some(&chunk) {
+            self.lru.retain(|c| *c != chunk);
+            self.lru.push_back(chunk);
+        }
+        Ok(self.cache.get_mut(&chunk).unwrap())
+    }
+
+    fn read_chunk(&mut self, chunk: u64) -> io::Result<Vec<u8>> {
+        let chunk_range = self.chunk_range(chunk);
+        let length = (chunk_range.end - chunk_range.start) as usize;
+        let entry = self.index[chunk as usize];
+        if entry.encoding == Encoding::Zero {
+            return Ok(vec![0u8; length]);
+        }
+        let mut stored = vec![0u8; entry.length as usize];
+        self.file.seek(SeekFrom::Start(entry.offset))?;
+        self.file.read_exact(&mut stored)?;
+        match entry.encoding {
+            Encoding::Lz4 => lz4::decompress(&stored, length),
+            Encoding::Stored if stored.len() == length => Ok(stored),
+            _ => Err(invalid_data("Corrupt chunk in compressed image")),
+        }
+    }
+
+    fn evict(&mut self, max_cached: usize) -> io::Result<()> {
+        while self.lru.len() > max_cached {
+            let chunk = self.lru.pop_front().unwrap();
+            let data = self.cache.remove(&chunk).unwrap();
+            self.flush_chunk(chunk, &data)?;
+        }
+        Ok(())
+    }
+
+    fn flush_chunk(&mut self, chunk: u64, data: &[u8]) -> io::Result<()> {
+        if data.iter().all(|v| *v == 0) {
+            self.set_index_entry(chunk, IndexEntry::zero());
+            return Ok(());
+        }
+        let compressed = lz4::compress(data);
+        let (stored, encoding) = if compressed.len() < data.len() {
+            (&compressed[..], Encoding::Lz4)
+        } else {
+            (data, Encoding::Stored)
+        };
+        let offset = self.allocate_extent(stored.len() as u64);
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(stored)?;
+        let entry = IndexEntry {
+            offset: offset,
+            length: stored.len() as u32,
+            encoding: encoding,
+        };
+        self.set_index_entry(chunk, entry);
+        Ok(())
+    }
+
+    fn set_index_entry(&mut self, chunk: u64, entry: IndexEntry) {
+        let previous = self.index[chunk as usize];
+        if previous.encoding != Encoding::Zero {
+            self.superseded_extents.push((previous.offset, previous.length as u64));
+        }
+        self.index[chunk as usize] = entry;
+        self.dirty_entries.insert(chunk);
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.evict(0)?;
+        if self.dirty_entries.is_empty() {
+            return self.file.sync_all();
+        }
+        // Extents must be durable before the index refers to them
+        self.file.sync_all()?;
+        let dirty: Vec<u64> = self.dirty_entries.iter().cloned().collect();
+        let mut run_start = 0;
+        while run_start < dirty.len() {
+            let mut run_end = run_start + 1;
+            while run_end < dirty.len() && dirty[run_end] == dirty[run_end - 1] + 1 {
+                run_end += 1;
+            }
+            let mut bytes = Vec::with_capacity((run_end - run_start) * INDEX_ENTRY_SIZE as usize);
+            for chunk in &dirty[run_start..run_end] {
+                bytes.extend_from_slice(&self.index[*chunk as usize].to_bytes());
+            }
+            self.file.seek(SeekFrom::Start(HEADER_SIZE + dirty[run_start] * INDEX_ENTRY_SIZE))?;
+            self.file.write_all(&bytes)?;
+            run_start = run_end;
+        }
+        self.file.sync_all()?;
+        self.dirty_entries.clear();
+        // The index on disk no longer refers to the extents superseded since the last sync
+        for (offset, length) in mem::replace(&mut self.superseded_extents, Vec::new()) {
+            self.free_extent(offset, length);
+        }
+        self.truncate_free_space()
+    }
+
+    // Returns free space at the end of the file to the filesystem
+    fn truncate_free_space(&mut self) -> io::Result<()> {
+        let last = self.free_extents.iter().next_back().map(|(&offset, &length)| (offset, length));
+        match last {
+            Some((offset, length)) if offset + length == self.end => {
+                self.free_extents.remove(&offset);
+                self.end = offset;
+                self.file.set_len(offset)
+            },
+            _ => Ok(()),
+        }
+    }
+
+    pub fn read_embedded_map(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match self.map_extent {
+            Some(extent) => {
+                let mut data = vec![0u8; extent.length as usize];
+                self.file.seek(SeekFrom::Start(extent.offset))?;
+                self.file.read_exact(&mut data)?;
+                Ok(Some(data))
+            },
+            None => Ok(None),
+        }
+    }
+
+    pub fn embed_map(&mut self, data: &[u8]) -> io::Result<()> {
+        let length = data.len() as u64;
+        let mut extent = match self.spare_map_extent {
+            Some(extent) if extent.capacity >= length => extent,
+            spare => {
+                // The header no longer refers to a spare extent, so one too small can be reused
+                if let Some(spare) = spare {
+                    self.free_extent(spare.offset, spare.capacity);
+                }
+                let capacity = cmp::max(length * 2, MIN_MAP_CAPACITY);
+                MapExtent {
+                    offset: self.allocate_extent(capacity),
+                    length: 0,
+                    capacity: capacity,
+                }
+            },
+        };
+        extent.length = length;
+        self.file.seek(SeekFrom::Start(extent.offset))?;
+        self.file.write_all(data)?;
+        self.file.sync_all()?;
+
+        let mut fields = [0u8; 16];
+        put_u64(&mut fields[0..8], extent.offset);
+        put_u64(&mut fields[8..16], extent.length);
+        self.file.seek(SeekFrom::Start(24))?;
+        self.file.write_all(&fields)?;
+        self.file.sync_all()?;
+        self.spare_map_extent = self.map_extent;
+        self.map_extent = Some(extent);
+        Ok(())
+    }
+
+    pub fn export_raw(&mut self, path: &Path) -> io::Result<()> {
+        let mut output = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(path)?;
+        output.set_len(self.size_bytes)?;
+        for chunk in 0..(self.index.len() as u64) {
+            if !self.cache.contains_key(&chunk) && self.index[chunk as usize].encoding == Encoding::Zero {
+                continue;
+            }
+            let chunk_range = self.chunk_range(chunk);
+            let mut data = vec![0u8; (chunk_range.end - chunk_range.start) as usize];
+            self.read_at(chunk_range.start, &mut data)?;
+            output.seek(SeekFrom::Start(chunk_range.start))?;
+            output.write_all(&data)?;
+        }
+        output.sync_all()
+    }
+}
+
+impl Sink for CompressedImage {
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        CompressedImage::write_at(self, offset, data)
+    }
+
+    fn write_zeros(&mut self, range: Range<u64>) -> io::Result<()> {
+        CompressedImage::write_zeros(self, range)
+    }
+
+    fn read_at(&mut self, offset: u64, data: &mut [u8]) -> io::Result<()> {
+        CompressedImage::read_at(self, offset, data)
+    }
+
+    fn is_range_zero(&mut self, range: Range<u64>) -> io::Result<bool> {
+        CompressedImage::is_range_zero(self, range)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        CompressedImage::sync(self)
+    }
+
+    fn get_size_bytes(&self) -> io::Result<u64> {
+        Ok(CompressedImage::get_size_bytes(self))
+    }
+
+    fn get_allocated_bytes(&self) -> io::Result<u64> {
+        CompressedImage::get_allocated_bytes(self)
+    }
+
+    fn embed_map(&mut self, data: &[u8]) -> io::Result<()> {
+        CompressedImage::embed_map(self, data)
+    }
+
+    fn read_embedded_map(&mut self) -> io::Result<Option<Vec<u8>>> {
+        CompressedImage::read_embedded_map(self)
+    }
+}
+
+impl Drop for CompressedImage {
+    fn drop(&mut self) {
+        // Panicking here would abort a rescue already stopping because of an error
+        let _ = self.sync();
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn put_u32(bytes: &mut [u8], value: u32) {
+    for (idx, byte) in bytes[0..4].iter_mut().enumerate() {
+        *byte = (value >> (idx * 8)) as u8;
+    }
+}
+
+fn put_u64(bytes: &mut [u8], value: u64) {
+    for (idx, byte) in bytes[0..8].iter_mut().enumerate() {
+        *byte = (value >> (idx * 8)) as u8;
+    }
+}
+
+fn get_u32(bytes: &[u8]) -> u32 {
+    bytes[0..4].iter().rev().fold(0, |value, byte| value << 8 | *byte as u32)
+}
+
+fn get_u64(bytes: &[u8]) -> u64 {
+    bytes[0..8].iter().rev().fold(0, |value, byte| value << 8 | *byte as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use testing::noise;
+
+    fn test_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("ddarecover-image-{}-{}", process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn read_all(image: &mut CompressedImage) -> Vec<u8> {
+        let mut data = vec![0u8; image.get_size_bytes() as usize];
+        image.read_at(0, &mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn round_trip_and_reopen() {
+        let path = test_path("round-trip");
+        let size = 5 * 65536 + 12345;
+        let mut expected = vec![0u8; size];
+        {
+            let mut image = CompressedImage::create(&path, size as u64, 65536).unwrap();
+            let offsets = noise(1, 200);
+            for (idx, value) in offsets.iter().enumerate() {
+                let offset = (*value as usize * 1601 + idx * 97) % size;
+                let length = cmp::min(1000 + idx * 37, size - offset);
+                if idx % 4 == 3 {
+                    image.write_zeros(offset as u64..(offset + length) as u64).unwrap();
+                    for byte in &mut expected[offset..(offset + length)] {
+                        *byte = 0;
+                    }
+                } else {
+                    // Alternately compressible and incompressible
+                    let data = if idx % 2 == 0 { vec![idx as u8; length] } else { noise(idx as u64, length) };
+                    image.write_at(offset as u64, &data).unwrap();
+                    expected[offset..(offset + length)].copy_from_slice(&data);
+                }
+                if idx % 50 == 0 {
+                    image.sync().unwrap();
+                }
+            }
+            assert!(read_all(&mut image) == expected);
+        }
+
+        let mut image = CompressedImage::open(&path, size as u64).unwrap();
+        assert!(read_all(&mut image) == expected);
+        assert!(image.is_range_zero(0..0).unwrap());
+        let raw_path = test_path("round-trip-raw");
+        image.export_raw(&raw_path).unwrap();
+        assert!(fs::read(&raw_path).unwrap() == expected);
+        fs::remove_file(&raw_path).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn size_must_match_on_reopen() {
+        let path = test_path("size");
+        CompressedImage::create(&path, 100000, 65536).unwrap();
+        assert!(CompressedImage::open(&path, 100000).is_ok());
+        assert!(CompressedImage::open(&path, 200000).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn zero_chunks_take_no_space() {
+        let path = test_path("zeros");
+        let mut image = CompressedImage::create(&path, 4 << 20, 1 << 20).unwrap();
+        let empty_length = fs::metadata(&path).unwrap().len();
+        image.write_at(1 << 20, &noise(2, 1 << 20)).unwrap();
+        image.sync().unwrap();
+        assert!(fs::metadata(&path).unwrap().len() > empty_length);
+        assert!(!image.is_range_zero((1 << 20)..(2 << 20)).unwrap());
+        image.write_zeros((1 << 20)..(2 << 20)).unwrap();
+        assert!(image.is_range_zero(0..(4 << 20)).unwrap());
+        drop(image);
+
+        let mut image = CompressedImage::open_existing(&path).unwrap();
+        assert!(image.is_range_zero(0..(4 << 20)).unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+
+    // Each sync stores partly written chunks again, so the extents they supersede must be reused
+    #[test]
+    fn repeated_syncs_reuse_space() {
+        let path = test_path("syncs");
+        let chunk_size = 1 << 20;
+        let data = noise(3, chunk_size);
+        let piece = chunk_size / 16;
+        {
+            let mut image = CompressedImage::create(&path, 8 << 20, chunk_size as u32).unwrap();
+            for idx in 0..16 {
+                image.write_at((idx * piece) as u64, &data[(idx * piece)..((idx + 1) * piece)]).unwrap();
+                image.sync().unwrap();
+            }
+        }
+        let limit = 4 * chunk_size as u64;
+        assert!(fs::metadata(&path).unwrap().len() < limit);
+
+        // Space superseded before the image was closed is found again when it is reopened
+        let mut image = CompressedImage::open_existing(&path).unwrap();
+        for idx in 0..16 {
+            let mut changed = data[(idx * piece)..((idx + 1) * piece)].to_vec();
+            changed.reverse();
+            image.write_at((idx * piece) as u64, &changed).unwrap();
+            image.sync().unwrap();
+        }
+        assert!(fs::metadata(&path).unwrap().len() < limit);
+        let mut start = vec![0u8; piece];
+        image.read_at(0, &mut start).unwrap();
+        let mut expected = data[0..piece].to_vec();
+        expected.reverse();
+        assert!(start == expected);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn embedded_map_survives_reopen() {
+        let path = test_path("map");
+        {
+            let mut image = CompressedImage::create(&path, 1 << 20, 65536).unwrap();
+            assert_eq!(image.read_embedded_map().unwrap(), None);
+            image.embed_map(b"first map").unwrap();
+            image.write_at(0, &noise(4, 100000)).unwrap();
+            // Too large for either extent allocated so far
+            image.embed_map(&vec![b'x'; 10000]).unwrap();
+            image.embed_map(b"third map").unwrap();
+        }
+        let mut image = CompressedImage::open_existing(&path).unwrap();
+        assert_eq!(image.read_embedded_map().unwrap(), Some(b"third map".to_vec()));
+        let mut data = vec![0u8; 100000];
+        image.read_at(0, &mut data).unwrap();
+        assert!(data == noise(4, 100000));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,137 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+// A Unix domain socket through which an operator can intervene in a running rescue. Clients send
+// one command per line and receive one line in reply, e.g. with `socat - UNIX-CONNECT:PATH`.
+//
+// Connections are served by their own threads, which pass commands to the rescue to be carried
+// out between reads. A reply is therefore only sent once the rescue has got round to the command.
+
+pub const USAGE: &'static str = "pause, resume, sync, queue-depth N, skip, next-phase or status";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Command {
+    // Stop submitting reads once those in flight have completed
+    Pause,
+    Resume,
+    // Sync the output and write the map immediately
+    Sync,
+    // Limit the number of reads in flight
+    QueueDepth(usize),
+    // Abandon the area currently being read and continue after it
+    Skip,
+    // Abandon the current phase
+    NextPhase,
+    Status,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match (fields.get(0).cloned(), fields.len()) {
+            (Some("pause"), 1) => Ok(Command::Pause),
+            (Some("resume"), 1) => Ok(Command::Resume),
+            (Some("sync"), 1) => Ok(Command::Sync),
+            (Some("queue-depth"), 2) => match fields[1].parse::<usize>() {
+                Ok(depth) if depth > 0 => Ok(Command::QueueDepth(depth)),
+                _ => Err(format!("invalid queue depth '{}'", fields[1])),
+            },
+            (Some("skip"), 1) => Ok(Command::Skip),
+            (Some("next-phase"), 1) => Ok(Command::NextPhase),
+            (Some("status"), 1) => Ok(Command::Status),
+            _ => Err(format!("unknown command '{}', expected {}", line.trim(), USAGE)),
+        }
+    }
+}
+
+// A command awaiting a reply
+#[derive(Debug)]
+pub struct ControlRequest {
+    pub command: Command,
+    reply: Sender<String>,
+}
+
+impl ControlRequest {
+    pub fn reply(self, text: &str) {
+        // The client may have gone away in the meantime
+        let _ = self.reply.send(String::from(text));
+    }
+}
+
+#[derive(Debug)]
+pub struct ControlSocket {
+    path: PathBuf,
+    requests: Receiver<ControlRequest>,
+}
+
+impl ControlSocket {
+    pub fn bind(path: &Path) -> io::Result<ControlSocket> {
+        // A socket left behind by an earlier run which did not exit cleanly can be replaced
+        if path.exists() {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, "Control socket is in use by another process"));
+            }
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if let Ok(stream) = stream {
+                    let sender = sender.clone();
+                    thread::spawn(move || {
+                        let _ = serve(stream, sender);
+                    });
+                }
+            }
+        });
+        Ok(ControlSocket {
+            path: path.to_path_buf(),
+            requests: receiver,
+        })
+    }
+
+    // The next command waiting to be carried out, if any
+    pub fn try_next(&self) -> Option<ControlRequest> {
+        self.requests.try_recv().ok()
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn serve(stream: UnixStream, requests: Sender<ControlRequest>) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match Command::parse(&line) {
+            Ok(command) => {
+                let (sender, receiver) = mpsc::channel();
+                let request = ControlRequest {
+                    command: command,
+                    reply: sender,
+                };
+                if requests.send(request).is_err() {
+                    return Ok(());
+                }
+                match receiver.recv() {
+                    Ok(reply) => reply,
+                    Err(_) => return Ok(()),
+                }
+            },
+            Err(message) => format!("error: {}", message),
+        };
+        writeln!(writer, "{}", reply)?;
+    }
+    Ok(())
+}
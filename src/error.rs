@@ -0,0 +1,146 @@
+use nix;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::io;
+use std::path::PathBuf;
+
+// The ways in which a rescue can fail, grouped by what the operator has to do about them. Each has
+// its own exit status so that scripts driving a rescue can tell them apart:
+//
+//   1  any other error, such as a log which cannot be written, or an internal error
+//   2  invalid command line
+//   3  the input device cannot be opened or queried
+//   4  the map, or a file kept alongside it, cannot be read or is invalid
+//   5  the output cannot be opened, read or written
+//   6  the output or a map does not match the geometry of the input device
+//   7  submitting a read to the device or collecting its result failed
+//   8  verification found rescued regions of the output which differ from the input
+
+#[derive(Debug)]
+pub enum RecoverError {
+    Usage(String),
+    // The path of the device, and what went wrong
+    Device(String, Box<Error>),
+    MapParse(PathBuf, Box<Error>),
+    Output(Box<Error>),
+    GeometryMismatch(String),
+    // Only for submitting reads of the input device and collecting their results
+    Aio(nix::Error),
+    // The number of bytes of the output which differ from the input
+    VerifyMismatch(u64),
+    Other(Box<Error>),
+}
+
+impl RecoverError {
+    // Errors from opening the input device, unless they are more specific
+    pub fn device<E: Into<Box<Error>>>(path: &str, err: E) -> RecoverError {
+        Self::classify(err.into(), |err| RecoverError::Device(String::from(path), err))
+    }
+
+    pub fn map<E: Into<Box<Error>>>(path: PathBuf, err: E) -> RecoverError {
+        Self::classify(err.into(), |err| RecoverError::MapParse(path, err))
+    }
+
+    pub fn output<E: Into<Box<Error>>>(err: E) -> RecoverError {
+        Self::classify(err.into(), RecoverError::Output)
+    }
+
+    // Recovers the category of an error which was passed on as a plain `Error`, falling back on
+    // `otherwise` for errors which were not given one where they arose
+    fn classify<F>(err: Box<Error>, otherwise: F) -> RecoverError where F: FnOnce(Box<Error>) -> RecoverError {
+        if let Some(message) = get_mismatch(&*err) {
+            return RecoverError::GeometryMismatch(message);
+        }
+        match err.downcast::<RecoverError>() {
+            Ok(err) => *err,
+            Err(err) => otherwise(err),
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            RecoverError::Other(_) => 1,
+            RecoverError::Usage(_) => 2,
+            RecoverError::Device(..) => 3,
+            RecoverError::MapParse(..) => 4,
+            RecoverError::Output(_) => 5,
+            RecoverError::GeometryMismatch(_) => 6,
+            RecoverError::Aio(_) => 7,
+            RecoverError::VerifyMismatch(_) => 8,
+        }
+    }
+}
+
+impl From<Box<Error>> for RecoverError {
+    fn from(err: Box<Error>) -> RecoverError {
+        Self::classify(err, RecoverError::Other)
+    }
+}
+
+impl From<io::Error> for RecoverError {
+    fn from(err: io::Error) -> RecoverError {
+        Self::classify(Box::new(err), RecoverError::Other)
+    }
+}
+
+impl Display for RecoverError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RecoverError::Usage(ref message) => write!(f, "{}", message),
+            RecoverError::Device(ref path, ref err) => write!(f, "Unable to use input device {}: {}", path, err),
+            RecoverError::MapParse(ref path, ref err) => write!(f, "Unable to read {}: {}", path.display(), err),
+            RecoverError::Output(ref err) => write!(f, "Unable to access the output: {}", err),
+            RecoverError::GeometryMismatch(ref message) => write!(f, "{}", message),
+            RecoverError::Aio(ref err) => write!(f, "Asynchronous I/O on the input device failed: {}", err),
+            RecoverError::VerifyMismatch(bytes) => write!(f, "{} bytes of the output differ from the input", bytes),
+            RecoverError::Other(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for RecoverError {
+    fn description(&self) -> &str {
+        "Rescue failed."
+    }
+}
+
+// A size or sector size which does not match that of the input device. It can be carried inside
+// an `io::Error`, for the many functions which return those.
+#[derive(Debug)]
+pub struct MismatchError {
+    message: String,
+}
+
+impl MismatchError {
+    pub fn new(message: &str) -> MismatchError {
+        MismatchError {
+            message: String::from(message),
+        }
+    }
+
+    pub fn into_io_error(self) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, self)
+    }
+}
+
+impl Display for MismatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for MismatchError {
+    fn description(&self) -> &str {
+        "Geometry mismatch."
+    }
+}
+
+fn get_mismatch(err: &(Error + 'static)) -> Option<String> {
+    if let Some(mismatch) = err.downcast_ref::<MismatchError>() {
+        return Some(mismatch.message.clone());
+    }
+    match err.downcast_ref::<io::Error>().and_then(|err| err.get_ref()) {
+        Some(inner) => inner.downcast_ref::<MismatchError>().map(|mismatch| mismatch.message.clone()),
+        None => None,
+    }
+}
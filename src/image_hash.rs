@@ -0,0 +1,452 @@
+use atomic_file;
+use block::Buffer;
+use error::MismatchError;
+use map_file::{MapFile, SectorState};
+use parse_error::ParseError;
+use phase::Phase;
+use sha256::{self, Digest, Sha256};
+use sink::Sink;
+use std::cmp;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+// Incremental SHA-256 digests of a rescued image. The image is divided into fixed-size chunks,
+// each of which is hashed once its contents can no longer change: when it has been entirely
+// rescued, or when the rescue has finished. A digest of the whole image is accumulated from the
+// start of the image as chunks are hashed, and completed when the last chunk is reached.
+//
+// Digests are stored in a text file alongside the map. Each chunk line gives the offset and
+// length of the chunk in the same form as the map file, followed by its digest.
+
+pub const SUFFIX: &'static str = "sha256";
+// Must be a multiple of the SHA-256 block size so that the whole-image state can be saved between
+// chunks
+pub const DEFAULT_CHUNK_SIZE: u64 = 1 << 20;
+// O_DIRECT outputs require aligned reads
+const BUFFER_ALIGNMENT: usize = 4096;
+
+#[derive(Debug)]
+pub struct ImageHash {
+    size_bytes: u64,
+    chunk_size: u64,
+    digests: Vec<Option<Digest>>,
+    // Everything before image.get_length() has been included in the whole-image digest
+    image: Sha256,
+    image_digest: Option<Digest>,
+    buffer: Buffer,
+}
+
+impl ImageHash {
+    pub fn new(size_bytes: u64, chunk_size: u64) -> ImageHash {
+        assert!(chunk_size > 0 && chunk_size % sha256::BLOCK_SIZE as u64 == 0);
+        let chunks = (size_bytes + chunk_size - 1) / chunk_size;
+        ImageHash {
+            size_bytes: size_bytes,
+            chunk_size: chunk_size,
+            digests: vec![None; chunks as usize],
+            image: Sha256::new(),
+            image_digest: None,
+            buffer: Buffer::allocate_aligned(chunk_size as usize, BUFFER_ALIGNMENT),
+        }
+    }
+
+    // The digest file accompanying the map at `map_path`
+    pub fn get_path(map_path: &Path) -> PathBuf {
+        atomic_file::generation_path(map_path, SUFFIX)
+    }
+
+    // Returns `None` if there is no digest file at `path`
+    pub fn read_from_path(path: &Path, size_bytes: u64) -> Result<Option<ImageHash>, Box<Error>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let reader = BufReader::new(File::open(path)?);
+        let mut chunk_size = None;
+        let mut result: Option<ImageHash> = None;
+        for line in reader.lines() {
+            let line = line?;
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() || fields[0].starts_with('#') {
+                continue;
+            }
+            match (fields[0], &mut result) {
+                ("chunk_size", _) if fields.len() == 2 => chunk_size = Some(parse_hex(fields[1])?),
+                ("size", &mut None) if fields.len() == 2 => {
+                    if parse_hex(fields[1])? != size_bytes {
+                        return Err(Box::new(MismatchError::new("Size of digest file does not match device")));
+                    }
+                    match chunk_size {
+                        Some(chunk_size) if chunk_size > 0 && chunk_size % sha256::BLOCK_SIZE as u64 == 0 => {
+                            result = Some(ImageHash::new(size_bytes, chunk_size));
+                        },
+                        _ => return Err(Box::new(ParseError::new("digest file chunk size"))),
+                    }
+                },
+                ("image_state", &mut Some(ref mut hash)) if fields.len() == 3 => {
+                    let length = parse_hex(fields[1])?;
+                    let bytes = parse_digest(fields[2])?;
+                    let mut state = [0u32; 8];
+                    for (idx, word) in state.iter_mut().enumerate() {
+                        for byte in &bytes[(idx * 4)..(idx * 4 + 4)] {
+                            *word = *word << 8 | *byte as u32;
+                        }
+                    }
+                    if length % hash.chunk_size != 0 || length > hash.size_bytes {
+                        return Err(Box::new(ParseError::new("digest file image state")));
+                    }
+                    hash.image = Sha256::from_state(state, length);
+                },
+                ("image_sha256", &mut Some(ref mut hash)) if fields.len() == 2 => {
+                    hash.image_digest = Some(parse_digest(fields[1])?);
+                },
+                (_, &mut Some(ref mut hash)) if fields.len() == 3 => {
+                    let offset = parse_hex(fields[0])?;
+                    let length = parse_hex(fields[1])?;
+                    let chunk = offset / hash.chunk_size;
+                    if offset % hash.chunk_size != 0 || offset >= hash.size_bytes || hash.chunk_range(chunk) != (offset..(offset + length)) {
+                        return Err(Box::new(ParseError::new("digest file chunk")));
+                    }
+                    hash.digests[chunk as usize] = Some(parse_digest(fields[2])?);
+                },
+                _ => return Err(Box::new(ParseError::new("digest file line"))),
+            }
+        }
+        match result {
+            Some(hash) => Ok(Some(hash)),
+            None => Err(Box::new(ParseError::new("digest file header"))),
+        }
+    }
+
+    pub fn write_to_path(&self, path: &Path) -> io::Result<()> {
+        atomic_file::replace(path, false, |file| self.write_to_stream(file))
+    }
+
+    pub fn write_to_stream<W: Write>(&self, write: W) -> io::Result<()> {
+        let mut write = BufWriter::new(write);
+        writeln!(&mut write, "# SHA-256 digests of rescued image chunks")?;
+        writeln!(&mut write, "chunk_size 0x{:08X}", self.chunk_size)?;
+        writeln!(&mut write, "size 0x{:08X}", self.size_bytes)?;
+        match (self.image_digest, self.image.get_state()) {
+            (Some(digest), _) => writeln!(&mut write, "image_sha256 {}", sha256::to_hex(&digest))?,
+            (None, Some((state, length))) => {
+                let mut bytes = Vec::with_capacity(sha256::DIGEST_SIZE);
+                for word in state.iter() {
+                    for shift in [24, 16, 8, 0].iter() {
+                        bytes.push((word >> shift) as u8);
+                    }
+                }
+                writeln!(&mut write, "image_state 0x{:08X} {}", length, sha256::to_hex(&bytes))?;
+            },
+            // The state can only be saved between chunks, and the digest is completed as soon as
+            // the last chunk has been included
+            (None, None) => return Err(io::Error::new(io::ErrorKind::InvalidData, "Image digest state is not on a chunk boundary")),
+        }
+        for (chunk, digest) in self.digests.iter().enumerate() {
+            if let Some(ref digest) = *digest {
+                let range = self.chunk_range(chunk as u64);
+                writeln!(&mut write, "0x{:08X}  0x{:08X}  {}", range.start, range.end - range.start, sha256::to_hex(digest))?;
+            }
+        }
+        write.flush()
+    }
+
+    pub fn get_chunk_size(&self) -> u64 {
+        self.chunk_size
+    }
+
+    pub fn get_image_digest(&self) -> Option<Digest> {
+        self.image_digest
+    }
+
+    fn chunk_range(&self, chunk: u64) -> Range<u64> {
+        let start = chunk * self.chunk_size;
+        start..cmp::min(start + self.chunk_size, self.size_bytes)
+    }
+
+    fn is_chunk_final(&self, map: &MapFile, chunk: u64) -> bool {
+        map.get_phase() == Phase::Finished
+            || map.iter_range(self.chunk_range(chunk)).all(|r| r.tag == SectorState::Rescued)
+    }
+
+    // Discards digests of chunks which the map says may still change, e.g. because the digest
+    // file was written after a map which was subsequently lost.
+    pub fn reconcile(&mut self, map: &MapFile) {
+        for chunk in 0..(self.digests.len() as u64) {
+            if self.digests[chunk as usize].is_some() && !self.is_chunk_final(map, chunk) {
+                self.digests[chunk as usize] = None;
+                // A completed digest may have been read from a file without the state behind it
+                if self.image_digest.is_some() || chunk * self.chunk_size < self.image.get_length() {
+                    self.image = Sha256::new();
+                    self.image_digest = None;
+                }
+            }
+        }
+    }
+
+    // Hashes any chunks overlapping `range` which have become final
+    pub fn update<S: Sink>(&mut self, map: &MapFile, range: Range<u64>, sink: &mut S) -> io::Result<()> {
+        if range.start >= range.end {
+            return Ok(());
+        }
+        let first = range.start / self.chunk_size;
+        let last = (range.end - 1) / self.chunk_size;
+        for chunk in first..(last + 1) {
+            if self.digests[chunk as usize].is_none() && self.is_chunk_final(map, chunk) {
+                self.hash_chunk(chunk, sink)?;
+            }
+        }
+        self.advance(sink)
+    }
+
+    // Hashes every chunk which is final but has no digest yet
+    pub fn catch_up<S: Sink>(&mut self, map: &MapFile, sink: &mut S) -> io::Result<()> {
+        let size_bytes = self.size_bytes;
+        self.update(map, 0..size_bytes, sink)
+    }
+
+    fn read_chunk<S: Sink>(&mut self, chunk: u64, sink: &mut S) -> io::Result<usize> {
+        let range = self.chunk_range(chunk);
+        let length = (range.end - range.start) as usize;
+        sink.read_at(range.start, &mut self.buffer.as_mut_slice()[..length])?;
+        Ok(length)
+    }
+
+    fn hash_chunk<S: Sink>(&mut self, chunk: u64, sink: &mut S) -> io::Result<()> {
+        let length = self.read_chunk(chunk, sink)?;
+        let data = &self.buffer.as_slice()[..length];
+        let mut hasher = Sha256::new();
+        hasher.update(data);
+        self.digests[chunk as usize] = Some(hasher.finish());
+        // Avoid reading the chunk again if it is next in the whole-image digest
+        if self.image_digest.is_none() && self.image.get_length() == chunk * self.chunk_size {
+            self.image.update(data);
+        }
+        Ok(())
+    }
+
+    fn advance<S: Sink>(&mut self, sink: &mut S) -> io::Result<()> {
+        while self.image_digest.is_none() {
+            let cursor = self.image.get_length();
+            if cursor == self.size_bytes {
+                self.image_digest = Some(self.image.clone().finish());
+                break;
+            }
+            let chunk = cursor / self.chunk_size;
+            if self.digests[chunk as usize].is_none() {
+                break;
+            }
+            let length = self.read_chunk(chunk, sink)?;
+            self.image.update(&self.buffer.as_slice()[..length]);
+        }
+        Ok(())
+    }
+}
+
+fn parse_hex(text: &str) -> Result<u64, ParseError> {
+    if !text.starts_with("0x") {
+        return Err(ParseError::new("hexadecimal value"));
+    }
+    u64::from_str_radix(&text[2..], 16).map_err(|_| ParseError::new("hexadecimal value"))
+}
+
+fn parse_digest(text: &str) -> Result<Digest, ParseError> {
+    match sha256::from_hex(text) {
+        Some(ref bytes) if bytes.len() == sha256::DIGEST_SIZE => {
+            let mut digest = [0u8; sha256::DIGEST_SIZE];
+            digest.copy_from_slice(bytes);
+            Ok(digest)
+        },
+        _ => Err(ParseError::new("SHA-256 digest")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use testing::{noise, test_dir, MemorySink};
+
+    // Several chunks, the last of them partial
+    const SIZE: u64 = 0x1000 + 0x340;
+    const CHUNK_SIZE: u64 = 0x400;
+
+    fn sink_with(data: &[u8]) -> MemorySink {
+        let mut sink = MemorySink::new(data.len() as u64);
+        sink.write_at(0, data).unwrap();
+        sink
+    }
+
+    fn digest_of(data: &[u8]) -> Digest {
+        let mut hasher = Sha256::new();
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    // Writes `hash` to a digest file in a directory named after `name` and reads it back
+    fn round_trip(name: &str, hash: &ImageHash) -> ImageHash {
+        let mut text = Vec::new();
+        hash.write_to_stream(&mut text).unwrap();
+        let dir = test_dir(name);
+        let path = ImageHash::get_path(&dir.join("drive.map"));
+        fs::write(&path, &text).unwrap();
+        let result = ImageHash::read_from_path(&path, SIZE).unwrap().unwrap();
+        let mut reread = Vec::new();
+        result.write_to_stream(&mut reread).unwrap();
+        assert!(reread == text);
+        fs::remove_dir_all(&dir).unwrap();
+        result
+    }
+
+    #[test]
+    fn final_chunks_are_hashed() {
+        let data = noise(1, SIZE as usize);
+        let mut sink = sink_with(&data);
+        let mut map = MapFile::new(SIZE);
+        map.put(0x400..0x900, SectorState::Rescued);
+        let mut hash = ImageHash::new(SIZE, CHUNK_SIZE);
+        hash.update(&map, 0x400..0x900, &mut sink).unwrap();
+
+        // Only the chunk which is entirely rescued, and the whole image is not started
+        assert_eq!(hash.digests[0], None);
+        assert_eq!(hash.digests[1], Some(digest_of(&data[0x400..0x800])));
+        assert_eq!(hash.digests[2], None);
+        assert_eq!(hash.image.get_length(), 0);
+        assert_eq!(hash.get_image_digest(), None);
+    }
+
+    #[test]
+    fn digest_file_round_trip() {
+        let data = noise(2, SIZE as usize);
+        let mut sink = sink_with(&data);
+        let mut map = MapFile::new(SIZE);
+        map.put(0..0x800, SectorState::Rescued);
+        map.put(0xc00..0x1000, SectorState::Rescued);
+        let mut hash = ImageHash::new(SIZE, CHUNK_SIZE);
+        hash.catch_up(&map, &mut sink).unwrap();
+        assert_eq!(hash.image.get_length(), 0x800);
+
+        let reread = round_trip("hash-round-trip", &hash);
+        assert_eq!(reread.chunk_size, CHUNK_SIZE);
+        assert_eq!(reread.digests, hash.digests);
+        assert_eq!(reread.image.get_state(), hash.image.get_state());
+        assert_eq!(reread.get_image_digest(), None);
+    }
+
+    #[test]
+    fn resumed_image_state_gives_the_same_digest() {
+        let data = noise(3, SIZE as usize);
+        let mut sink = sink_with(&data);
+        let mut map = MapFile::new(SIZE);
+        map.put(0..0x800, SectorState::Rescued);
+        let mut hash = ImageHash::new(SIZE, CHUNK_SIZE);
+        hash.catch_up(&map, &mut sink).unwrap();
+
+        // Carry on from the saved state once the rest has been rescued
+        let mut hash = round_trip("hash-resume-state", &hash);
+        map.put(0..SIZE, SectorState::Rescued);
+        hash.catch_up(&map, &mut sink).unwrap();
+        assert_eq!(hash.get_image_digest(), Some(digest_of(&data)));
+        assert_eq!(hash.digests[4], Some(digest_of(&data[0x1000..])));
+
+        // And the completed digest survives being written and read again
+        let hash = round_trip("hash-resume-digest", &hash);
+        assert_eq!(hash.get_image_digest(), Some(digest_of(&data)));
+    }
+
+    #[test]
+    fn catch_up_hashes_chunks_with_bad_sectors_once_finished() {
+        let mut data = noise(4, SIZE as usize);
+        for value in &mut data[0x500..0x600] {
+            *value = 0;
+        }
+        let mut sink = sink_with(&data);
+        let mut map = MapFile::new(SIZE);
+        map.put(0..SIZE, SectorState::Rescued);
+        map.put(0x500..0x600, SectorState::Bad);
+        let mut hash = ImageHash::new(SIZE, CHUNK_SIZE);
+        hash.catch_up(&map, &mut sink).unwrap();
+        assert_eq!(hash.digests[1], None);
+        assert_eq!(hash.get_image_digest(), None);
+
+        map.set_phase(&Phase::Finished);
+        hash.catch_up(&map, &mut sink).unwrap();
+        assert_eq!(hash.digests[1], Some(digest_of(&data[0x400..0x800])));
+        assert_eq!(hash.get_image_digest(), Some(digest_of(&data)));
+    }
+
+    #[test]
+    fn reconcile_discards_digests_of_chunks_which_may_change() {
+        let data = noise(5, SIZE as usize);
+        let mut sink = sink_with(&data);
+        let mut map = MapFile::new(SIZE);
+        map.put(0..SIZE, SectorState::Rescued);
+        let mut hash = ImageHash::new(SIZE, CHUNK_SIZE);
+        hash.catch_up(&map, &mut sink).unwrap();
+        assert!(hash.get_image_digest().is_some());
+
+        // A chunk after the start no longer being rescued invalidates the whole-image digest too
+        map.put(0xc00..0xd00, SectorState::Untried);
+        hash.reconcile(&map);
+        assert_eq!(hash.digests.iter().filter(|d| d.is_none()).count(), 1);
+        assert_eq!(hash.digests[3], None);
+        assert_eq!(hash.get_image_digest(), None);
+        assert_eq!(hash.image.get_length(), 0);
+
+        map.put(0xc00..0xd00, SectorState::Rescued);
+        hash.catch_up(&map, &mut sink).unwrap();
+        assert_eq!(hash.get_image_digest(), Some(digest_of(&data)));
+    }
+
+    #[test]
+    fn reconcile_keeps_the_image_state_before_a_discarded_chunk() {
+        let data = noise(6, SIZE as usize);
+        let mut sink = sink_with(&data);
+        let mut map = MapFile::new(SIZE);
+        map.put(0..0x800, SectorState::Rescued);
+        map.put(0xc00..0x1000, SectorState::Rescued);
+        let mut hash = ImageHash::new(SIZE, CHUNK_SIZE);
+        hash.catch_up(&map, &mut sink).unwrap();
+
+        map.put(0xc00..0xd00, SectorState::Bad);
+        hash.reconcile(&map);
+        assert_eq!(hash.digests[3], None);
+        assert_eq!(hash.image.get_length(), 0x800);
+    }
+
+    #[test]
+    fn invalid_digest_files_are_rejected() {
+        let dir = test_dir("hash-invalid");
+        let path = ImageHash::get_path(&dir.join("drive.map"));
+        assert!(ImageHash::read_from_path(&path, SIZE).unwrap().is_none());
+
+        let header = "chunk_size 0x00000400\nsize 0x00001340\n";
+        let digest = sha256::to_hex(&[0u8; sha256::DIGEST_SIZE]);
+        for contents in [
+            String::from("chunk_size 0x00000400\nsize 0x00002000\n"),
+            String::from("chunk_size 0x00000401\nsize 0x00001340\n"),
+            String::from("size 0x00001340\n"),
+            String::from("# No header\n"),
+            format!("{}0x00000200  0x00000400  {}\n", header, digest),
+            format!("{}0x00001000  0x00000400  {}\n", header, digest),
+            format!("{}image_state 0x00000200 {}\n", header, digest),
+            format!("{}image_sha256 0x1234\n", header),
+            format!("{}unknown line\n", header),
+        ].iter() {
+            fs::write(&path, contents).unwrap();
+            assert!(ImageHash::read_from_path(&path, SIZE).is_err(), "{}", contents);
+        }
+        fs::write(&path, format!("{}0x00001000  0x00000340  {}\n", header, digest)).unwrap();
+        assert!(ImageHash::read_from_path(&path, SIZE).unwrap().is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn state_between_blocks_is_not_written() {
+        let mut hash = ImageHash::new(SIZE, CHUNK_SIZE);
+        hash.image.update(&[0u8; 10]);
+        assert!(hash.write_to_stream(&mut Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,37 @@
+use block::{Buffer, Request};
+use libc::c_int;
+use nix;
+
+// The device being rescued. Reads are submitted and their results collected later, so that several
+// can be in flight at once.
+pub trait Input {
+    fn submit_request(&mut self, req: Request) -> Result<(), nix::Error>;
+
+    // Writes the data read by a completed request to `fd` at the same offset. The buffer is
+    // returned with the request once the write completes.
+    fn submit_write(&mut self, fd: c_int, req: Request) -> Result<(), nix::Error>;
+
+    // Waits for a request in flight to complete
+    fn get_completed_request(&mut self) -> Result<Request, nix::Error>;
+
+    // The offset of the furthest read in flight, which is in the area being read
+    fn last_pending_read(&self) -> Option<u64>;
+
+    fn max_requests(&self) -> usize;
+
+    fn requests_avail(&self) -> usize {
+        self.max_requests() - self.requests_pending()
+    }
+
+    fn requests_pending(&self) -> usize;
+
+    fn get_block_size_physical(&self) -> usize;
+
+    fn get_sector_size(&self) -> usize;
+
+    fn get_size_bytes(&self) -> u64;
+
+    fn create_io_buffer(&self, sectors: usize) -> Buffer {
+        Buffer::allocate_aligned(sectors * self.get_sector_size(), self.get_sector_size())
+    }
+}
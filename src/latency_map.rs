@@ -0,0 +1,143 @@
+use atomic_file;
+use error::MismatchError;
+use parse_error::ParseError;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tagged_range::{self, TaggedRange};
+
+// The time taken by the most recent read of each region of the device, grouped into buckets. Areas
+// which a drive is struggling with tend to become slow before they become unreadable, so this
+// shows where it is degrading even while reads still succeed.
+//
+// The map is stored alongside the map file. After a header giving the size of the device, each
+// line gives the offset and length of a region in the same form as the map file, followed by the
+// bucket of its latency. Regions which have not been read are omitted.
+
+pub const SUFFIX: &'static str = "latency";
+// Upper bounds in milliseconds of each bucket but the last, which is unbounded
+pub const BUCKET_LIMITS_MS: [u64; 4] = [10, 100, 1000, 10000];
+pub const BUCKET_COUNT: usize = 5;
+
+#[derive(Clone, Debug)]
+pub struct LatencyMap {
+    size_bytes: u64,
+    buckets: TaggedRange<u8>,
+}
+
+impl LatencyMap {
+    pub fn new(size_bytes: u64) -> LatencyMap {
+        LatencyMap {
+            size_bytes: size_bytes,
+            buckets: TaggedRange::new(),
+        }
+    }
+
+    // The latency map accompanying the map at `map_path`
+    pub fn get_path(map_path: &Path) -> PathBuf {
+        atomic_file::generation_path(map_path, SUFFIX)
+    }
+
+    // Returns `None` if there is no latency map at `path`
+    pub fn read_from_path(path: &Path, size_bytes: u64) -> Result<Option<LatencyMap>, Box<Error>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let reader = BufReader::new(File::open(path)?);
+        let mut result: Option<LatencyMap> = None;
+        for line in reader.lines() {
+            let line = line?;
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() || fields[0].starts_with('#') {
+                continue;
+            }
+            match (fields[0], &mut result) {
+                ("size", &mut None) if fields.len() == 2 => {
+                    if parse_hex(fields[1])? != size_bytes {
+                        return Err(Box::new(MismatchError::new("Size of latency map does not match device")));
+                    }
+                    result = Some(LatencyMap::new(size_bytes));
+                },
+                (_, &mut Some(ref mut map)) if fields.len() == 3 => {
+                    let offset = parse_hex(fields[0])?;
+                    let length = parse_hex(fields[1])?;
+                    let bucket = match fields[2].parse::<u8>() {
+                        Ok(bucket) if (bucket as usize) < BUCKET_COUNT => bucket,
+                        _ => return Err(Box::new(ParseError::new("latency bucket"))),
+                    };
+                    if offset + length > map.size_bytes {
+                        return Err(Box::new(ParseError::new("latency map region")));
+                    }
+                    map.buckets.put(offset..(offset + length), bucket);
+                },
+                _ => return Err(Box::new(ParseError::new("latency map line"))),
+            }
+        }
+        match result {
+            Some(map) => Ok(Some(map)),
+            None => Err(Box::new(ParseError::new("latency map header"))),
+        }
+    }
+
+    pub fn write_to_path(&self, path: &Path) -> io::Result<()> {
+        atomic_file::replace(path, false, |file| self.write_to_stream(file))
+    }
+
+    pub fn write_to_stream<W: Write>(&self, write: W) -> io::Result<()> {
+        let mut write = BufWriter::new(write);
+        writeln!(&mut write, "# Latency of the most recent read of each region")?;
+        let names: Vec<String> = (0..BUCKET_COUNT).map(|b| format!("{} {}", b, bucket_name(b as u8))).collect();
+        writeln!(&mut write, "# Buckets: {}", names.join(", "))?;
+        writeln!(&mut write, "size 0x{:08X}", self.size_bytes)?;
+        for region in self.buckets.iter() {
+            writeln!(&mut write, "0x{:08X}  0x{:08X}  {}", region.start, region.length, region.tag)?;
+        }
+        write.flush()
+    }
+
+    pub fn record(&mut self, range: Range<u64>, latency: Duration) {
+        self.buckets.put(range, bucket(latency));
+    }
+
+    pub fn iter<'a>(&'a self) -> tagged_range::Iter<'a, u8> {
+        self.buckets.iter()
+    }
+
+    // The number of bytes in each bucket
+    pub fn get_histogram(&self) -> [u64; BUCKET_COUNT] {
+        let mut histogram = [0; BUCKET_COUNT];
+        for region in self.buckets.iter() {
+            histogram[region.tag as usize] += region.length;
+        }
+        histogram
+    }
+
+    // The number of bytes whose most recent read took a second or more
+    pub fn get_slow_bytes(&self) -> u64 {
+        let slow = bucket(Duration::from_secs(1));
+        self.buckets.iter().filter(|r| r.tag >= slow).map(|r| r.length).sum()
+    }
+}
+
+pub fn bucket(latency: Duration) -> u8 {
+    let ms = latency.as_secs() * 1000 + latency.subsec_nanos() as u64 / 1000000;
+    BUCKET_LIMITS_MS.iter().position(|limit| ms < *limit).unwrap_or(BUCKET_LIMITS_MS.len()) as u8
+}
+
+pub fn bucket_name(bucket: u8) -> String {
+    let format_ms = |ms: u64| if ms >= 1000 { format!("{}s", ms / 1000) } else { format!("{}ms", ms) };
+    match BUCKET_LIMITS_MS.get(bucket as usize) {
+        Some(limit) => format!("<{}", format_ms(*limit)),
+        None => format!(">={}", format_ms(BUCKET_LIMITS_MS[BUCKET_LIMITS_MS.len() - 1])),
+    }
+}
+
+fn parse_hex(text: &str) -> Result<u64, ParseError> {
+    if !text.starts_with("0x") {
+        return Err(ParseError::new("hexadecimal value"));
+    }
+    u64::from_str_radix(&text[2..], 16).map_err(|_| ParseError::new("hexadecimal value"))
+}
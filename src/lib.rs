@@ -1,3 +1,4 @@
+extern crate ansi_escapes;
 extern crate combine;
 extern crate libc;
 extern crate num;
@@ -6,9 +7,32 @@ extern crate num;
 extern crate nix;
 
 pub mod aio_abi;
+pub mod atomic_file;
 pub mod block;
+pub mod compressed_image;
+pub mod control;
+pub mod error;
+pub mod image_hash;
+pub mod input;
+pub mod latency_map;
+pub mod logs;
+pub mod lz4;
 pub mod map_file;
+pub mod metrics;
 pub mod out_file;
 pub mod parse_error;
 pub mod phase;
+pub mod qcow2;
+pub mod recover;
+pub mod sha256;
+pub mod signals;
+pub mod sink;
+pub mod statx_abi;
+pub mod status;
 pub mod tagged_range;
+pub mod tui;
+pub mod unstable_log;
+pub mod vote;
+
+#[cfg(test)]
+mod testing;
@@ -0,0 +1,170 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Optional logs of the progress of a rescue, kept for diagnosing a drive after the fact. Logs are
+// appended to, so that a rescue resumed several times produces a single history. Each run begins
+// with a comment line giving the columns which follow.
+
+// Suffix of the event log accompanying a map
+pub const EVENTS_SUFFIX: &'static str = "events";
+
+#[derive(Debug)]
+pub struct Log {
+    write: BufWriter<File>,
+}
+
+impl Log {
+    pub fn open(path: &Path, columns: &str) -> io::Result<Log> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let mut log = Log {
+            write: BufWriter::new(file),
+        };
+        writeln!(log.write, "# {}", columns)?;
+        log.write.flush()?;
+        Ok(log)
+    }
+
+    // Writes a line beginning with the current time
+    pub fn line(&mut self, text: &str) -> io::Result<()> {
+        writeln!(self.write, "{}  {}", format_timestamp(SystemTime::now()), text)
+    }
+
+    // Lines are buffered until flushed, since the reads log may receive thousands per second
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.write.flush()
+    }
+}
+
+// One line per second with the position, the read rates and the extent of bad areas
+#[derive(Debug)]
+pub struct RatesLog {
+    log: Log,
+    last_line: Option<(SystemTime, u64)>,
+}
+
+impl RatesLog {
+    pub fn open(path: &Path) -> io::Result<RatesLog> {
+        Ok(RatesLog {
+            log: Log::open(path, "time  ipos  current_rate  average_rate  bad_areas  bad_size")?,
+            last_line: None,
+        })
+    }
+
+    // Whether a second has passed since the last line
+    pub fn is_due(&self) -> bool {
+        match self.last_line {
+            None => true,
+            Some((time, _)) => seconds_since(time) >= 1.0,
+        }
+    }
+
+    // `read_bytes` and `elapsed` are the bytes read and seconds elapsed during this run
+    pub fn write(&mut self, pos: u64, read_bytes: u64, elapsed: u64, bad_areas: usize, bad_size: u64) -> io::Result<()> {
+        let now = SystemTime::now();
+        let current_rate = match self.last_line {
+            Some((time, previous_bytes)) if seconds_since(time) > 0.0 => {
+                ((read_bytes - previous_bytes) as f64 / seconds_since(time)) as u64
+            },
+            _ => 0,
+        };
+        let average_rate = if elapsed > 0 { read_bytes / elapsed } else { 0 };
+        self.log.line(&format!("0x{:08X}  {}  {}  {}  0x{:08X}", pos, current_rate, average_rate, bad_areas, bad_size))?;
+        self.log.flush()?;
+        self.last_line = Some((now, read_bytes));
+        Ok(())
+    }
+}
+
+// One line per completed read with its result and how long the device took to complete it
+#[derive(Debug)]
+pub struct ReadsLog {
+    log: Log,
+    last_flush: SystemTime,
+}
+
+impl ReadsLog {
+    pub fn open(path: &Path) -> io::Result<ReadsLog> {
+        Ok(ReadsLog {
+            log: Log::open(path, "time  offset  size  result  latency_us")?,
+            last_flush: SystemTime::now(),
+        })
+    }
+
+    // `result` is the number of bytes read, or a negated errno value
+    pub fn record(&mut self, offset: u64, size: u64, result: isize, latency: Option<Duration>) -> io::Result<()> {
+        let latency = match latency {
+            Some(latency) => format!("{}", latency.as_secs() * 1000000 + latency.subsec_nanos() as u64 / 1000),
+            None => String::from("-"),
+        };
+        self.log.line(&format!("0x{:08X}  0x{:08X}  {}  {}", offset, size, result, latency))?;
+        // Keep the log reasonably current for anyone following it
+        if seconds_since(self.last_flush) >= 1.0 {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.last_flush = SystemTime::now();
+        self.log.flush()
+    }
+}
+
+// One line per notable event, such as a change of phase or a new bad area, so that the course of
+// a rescue can be followed afterwards
+#[derive(Debug)]
+pub struct EventLog {
+    log: Log,
+}
+
+impl EventLog {
+    pub fn open(path: &Path) -> io::Result<EventLog> {
+        Ok(EventLog {
+            log: Log::open(path, "time  event  details")?,
+        })
+    }
+
+    pub fn record(&mut self, event: &str, details: &str) -> io::Result<()> {
+        self.log.line(format!("{:<11} {}", event, details).trim_end())?;
+        self.log.flush()
+    }
+}
+
+fn seconds_since(time: SystemTime) -> f64 {
+    match time.elapsed() {
+        Ok(duration) => duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9,
+        Err(_) => 0.0,
+    }
+}
+
+// An ISO 8601 UTC timestamp with millisecond precision, e.g. 2017-09-30T14:05:09.123Z
+pub fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    let seconds = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+    let seconds_of_day = seconds % 86400;
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year, month, day,
+            seconds_of_day / 3600, seconds_of_day / 60 % 60, seconds_of_day % 60,
+            since_epoch.subsec_nanos() / 1000000)
+}
+
+// Converts days since 1970-01-01 to a proleptic Gregorian date, using Howard Hinnant's
+// civil_from_days algorithm
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = (if z >= 0 { z } else { z - 146096 }) / 146097;
+    let day_of_era = z - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = (if month_index < 10 { month_index + 3 } else { month_index - 9 }) as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
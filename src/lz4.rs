@@ -0,0 +1,199 @@
+use std::io;
+
+// A compressor and decompressor for the LZ4 block format, as described at
+// https://github.com/lz4/lz4/blob/dev/doc/lz4_Block_format.md. The compressor is a simple greedy
+// one, which favours simplicity over compression ratio.
+
+const MIN_MATCH: usize = 4;
+const HASH_LOG: usize = 12;
+const MAX_DISTANCE: usize = 65535;
+// The last match must start at least this many bytes before the end of the block
+const MF_LIMIT: usize = 12;
+// The last bytes of a block are always encoded as literals
+const LAST_LITERALS: usize = 5;
+
+fn read_u32(data: &[u8], pos: usize) -> u32 {
+    (data[pos] as u32) | (data[pos + 1] as u32) << 8 | (data[pos + 2] as u32) << 16 | (data[pos + 3] as u32) << 24
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize
+}
+
+fn write_length(output: &mut Vec<u8>, mut length: usize) {
+    while length >= 255 {
+        output.push(255);
+        length -= 255;
+    }
+    output.push(length as u8);
+}
+
+fn write_sequence(output: &mut Vec<u8>, literals: &[u8], match_info: Option<(usize, usize)>) {
+    let literal_nibble = if literals.len() >= 15 { 15 } else { literals.len() };
+    let match_nibble = match match_info {
+        Some((_, length)) if length - MIN_MATCH >= 15 => 15,
+        Some((_, length)) => length - MIN_MATCH,
+        None => 0,
+    };
+    output.push((literal_nibble << 4 | match_nibble) as u8);
+    if literal_nibble == 15 {
+        write_length(output, literals.len() - 15);
+    }
+    output.extend_from_slice(literals);
+    if let Some((distance, length)) = match_info {
+        output.push(distance as u8);
+        output.push((distance >> 8) as u8);
+        if match_nibble == 15 {
+            write_length(output, length - MIN_MATCH - 15);
+        }
+    }
+}
+
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len() / 2 + 16);
+    // Positions are stored offset by one so that zero means empty
+    let mut table = vec![0usize; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut pos = 0;
+    if input.len() > MF_LIMIT {
+        let match_limit = input.len() - MF_LIMIT;
+        let match_end_limit = input.len() - LAST_LITERALS;
+        while pos < match_limit {
+            let sequence = read_u32(input, pos);
+            let slot = hash(sequence);
+            let candidate = table[slot];
+            table[slot] = pos + 1;
+            if candidate > 0 && pos - (candidate - 1) <= MAX_DISTANCE && read_u32(input, candidate - 1) == sequence {
+                let candidate = candidate - 1;
+                let mut length = MIN_MATCH;
+                while pos + length < match_end_limit && input[candidate + length] == input[pos + length] {
+                    length += 1;
+                }
+                write_sequence(&mut output, &input[anchor..pos], Some((pos - candidate, length)));
+                pos += length;
+                anchor = pos;
+            } else {
+                pos += 1;
+            }
+        }
+    }
+    write_sequence(&mut output, &input[anchor..], None);
+    output
+}
+
+fn read_length(input: &[u8], pos: &mut usize) -> io::Result<usize> {
+    let mut length = 0;
+    loop {
+        let value = *input.get(*pos).ok_or_else(truncated)?;
+        *pos += 1;
+        length += value as usize;
+        if value != 255 {
+            return Ok(length);
+        }
+    }
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Truncated LZ4 block")
+}
+
+fn corrupt() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Corrupt LZ4 block")
+}
+
+pub fn decompress(input: &[u8], output_length: usize) -> io::Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(output_length);
+    let mut pos = 0;
+    while pos < input.len() {
+        let token = input[pos];
+        pos += 1;
+        let mut literal_length = (token >> 4) as usize;
+        if literal_length == 15 {
+            literal_length += read_length(input, &mut pos)?;
+        }
+        if pos + literal_length > input.len() {
+            return Err(truncated());
+        }
+        output.extend_from_slice(&input[pos..(pos + literal_length)]);
+        pos += literal_length;
+        if pos == input.len() {
+            break;
+        }
+
+        if pos + 2 > input.len() {
+            return Err(truncated());
+        }
+        let distance = input[pos] as usize | (input[pos + 1] as usize) << 8;
+        pos += 2;
+        let mut match_length = (token & 0xf) as usize;
+        if match_length == 15 {
+            match_length += read_length(input, &mut pos)?;
+        }
+        match_length += MIN_MATCH;
+        if distance == 0 || distance > output.len() || output.len() + match_length > output_length {
+            return Err(corrupt());
+        }
+        // Matches may overlap the data they produce, so are copied a byte at a time
+        let start = output.len() - distance;
+        for offset in 0..match_length {
+            let value = output[start + offset];
+            output.push(value);
+        }
+    }
+    if output.len() != output_length {
+        return Err(corrupt());
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::noise;
+
+    fn samples(length: usize) -> Vec<Vec<u8>> {
+        let random = noise(12345, length);
+        vec![
+            vec![0; length],
+            random.clone(),
+            (0..length).map(|i| (i % 251) as u8 / 7).collect(),
+            // Runs of repetitive data between runs of random data
+            (0..length).map(|i| if i % 1000 < 500 { random[i] % 4 } else { 0 }).collect(),
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        for &length in [0, 1, 5, 12, 13, 17, 100, 4096, 65536, 1 << 20].iter() {
+            for (kind, data) in samples(length).iter().enumerate() {
+                let compressed = compress(data);
+                assert!(decompress(&compressed, length).unwrap() == *data, "length {} kind {}", length, kind);
+            }
+        }
+    }
+
+    #[test]
+    fn repetitive_data_compresses() {
+        let compressed = compress(&vec![0; 1 << 20]);
+        assert!(compressed.len() < 8192, "{} bytes", compressed.len());
+    }
+
+    // A block produced by the reference implementation, for "abcabcabcabcabcabcabcabcabcabc"
+    #[test]
+    fn decompresses_reference_block() {
+        let block = [0x3f, b'a', b'b', b'c', 0x03, 0x00, 0x03, 0x50, b'b', b'c', b'a', b'b', b'c'];
+        let expected = b"abcabcabcabcabcabcabcabcabcabc";
+        assert_eq!(decompress(&block, expected.len()).unwrap(), expected.to_vec());
+    }
+
+    #[test]
+    fn rejects_invalid_blocks() {
+        let data = samples(4096).pop().unwrap();
+        let compressed = compress(&data);
+        assert!(decompress(&compressed[..(compressed.len() / 2)], data.len()).is_err());
+        assert!(decompress(&compressed, data.len() - 1).is_err());
+        assert!(decompress(&compressed, data.len() + 1).is_err());
+        // A match reaching back before the start of the output
+        assert!(decompress(&[0x10, b'a', 0x02, 0x00, 0x00], 5).is_err());
+    }
+}
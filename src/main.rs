@@ -1,472 +1,304 @@
-extern crate ansi_escapes;
-extern crate ctrlc;
 extern crate ddarecover;
 extern crate getopts;
-extern crate nix;
 
-use ddarecover::block::{BlockDevice, Buffer, Request};
-use ddarecover::map_file::{MapFile, SectorState};
-use ddarecover::out_file::OutFile;
+use ddarecover::block::BlockDevice;
+use ddarecover::compressed_image::CompressedImage;
+use ddarecover::error::RecoverError;
+use ddarecover::out_file::{OutFile, OutFileOptions};
+use ddarecover::qcow2::Qcow2Image;
+use ddarecover::recover::{self, Recover, Settings, StatusFormat};
+use ddarecover::signals;
+use ddarecover::sink::Sink;
 use getopts::Options;
-use std::env;
 use std::cmp;
-use std::collections::{VecDeque, HashMap};
-use std::error::Error;
-use std::fs::File;
-use std::io::{self, Seek, SeekFrom, Write};
-use std::ops::Range;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::Instant;
+use std::process;
 
-const READ_BATCH_SIZE: usize = 128;
-const SYNC_INTERVAL: usize = 5 * 60;
-const REFRESH_INTERVAL: f32 = 0.5;
+// Added to the number of the signal which interrupted the rescue, as by the shell
+const EXIT_SIGNAL_BASE: i32 = 128;
 
-#[derive(Debug)]
-struct Stats {
-    good: u64,
-    bad: u64,
-    requests: u64,
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum OutputFormat {
+    Raw,
+    Compressed,
+    Qcow2,
 }
 
-impl Stats {
-    pub fn new() -> Stats {
-        Stats {
-            good: 0,
-            bad: 0,
-            requests: 0,
+impl OutputFormat {
+    fn from_name(name: &str) -> Option<OutputFormat> {
+        match name {
+            "raw" => Some(OutputFormat::Raw),
+            "compressed" => Some(OutputFormat::Compressed),
+            "qcow2" => Some(OutputFormat::Qcow2),
+            _ => None,
         }
     }
 }
 
-#[derive(Debug)]
-struct Recover {
-    block: BlockDevice,
-    map_file: MapFile,
-    map_file_path: PathBuf,
-    out_file: OutFile,
-    start: Instant,
-    last_sync: Instant,
-    last_success: Option<Instant>,
-    last_print: Option<Instant>,
-    histogram: HashMap<SectorState, u64>,
-    buffer_cache: Vec<Buffer>,
-    should_run_flag: Arc<AtomicBool>,
-    stats: Stats,
-}
-
-impl Recover {
-    pub fn new(infile_path: &str, outfile_path: &str, mapfile_path: &str) -> io::Result<Recover> {
-        let block = BlockDevice::open(infile_path).expect("Unable to open block device");
-        let map_path = Path::new(mapfile_path);
-        let map = if map_path.exists() {
-            let map_file = File::open(map_path).expect("Unable to open existing map file");
-            MapFile::read_from_stream(map_file).expect("Error reading map file")
-        } else {
-            let map = MapFile::new(block.get_size_bytes());
-            map.write_to_path(map_path).expect("Unable to create new map file");
-            map
-        };
-        assert_eq!(map.get_size_bytes(), block.get_size_bytes(), "Mismatch between device size and map file");
-        let outfile_path = Path::new(outfile_path);
-        let outfile = OutFile::open(outfile_path, block.get_size_bytes()).expect("Unable to open output file");
-
-        let histogram = map.get_histogram();
-        let should_run_flag = Arc::new(AtomicBool::new(true));
-        let result = Recover {
-            block: block,
-            map_file: map,
-            map_file_path: map_path.to_path_buf(),
-            out_file: outfile,
-            start: Instant::now(),
-            last_sync: Instant::now(),
-            last_success: None,
-            last_print: None,
-            histogram: histogram,
-            buffer_cache: Vec::new(),
-            should_run_flag: should_run_flag.clone(),
-            stats: Stats::new(),
-        };
-        ctrlc::set_handler(move || {
-            should_run_flag.store(false, Ordering::SeqCst);
-        }).expect("Error setting Ctrl-C handler");
-        Ok(result)
-    }
-
-    fn should_run(&self) -> bool {
-        self.should_run_flag.load(Ordering::SeqCst)
-    }
-
-    fn do_sync(&mut self) -> io::Result<()> {
-        self.out_file.sync()?;
-        self.map_file.write_to_path(&self.map_file_path)?;
-        self.last_sync = Instant::now();
-        Ok(())
-    }
-
-    fn update_status(&mut self) {
-        let now = Instant::now();
-        match self.last_print {
-            None => {
-                self.print_status(false);
-                self.last_print = Some(now);
-            },
-            Some(previous) => {
-                let duration = now.duration_since(previous);
-                let seconds = duration.as_secs() as f32 + duration.subsec_nanos() as f32 * 1e-9;
-                if seconds > REFRESH_INTERVAL {
-                    self.print_status(true);
-                    self.last_print = Some(now);
-                }
-            },
-        }
-    }
-
-    fn print_status(&self, overwrite: bool) {
-        let key_width = 13;
-        let value_width = 19;
-        if overwrite {
-            print!("{}{}", ansi_escapes::CursorLeft, ansi_escapes::CursorUp(7));
-        }
-        println!("Press Ctrl+C to exit.{}\n{}", ansi_escapes::EraseEndLine, ansi_escapes::EraseEndLine);
-        println!("{:>kw$}: {:vw$}{}", "Phase",
-                 format!("{} (pass {})", self.map_file.get_phase().name(), self.map_file.get_pass()),
-                 ansi_escapes::EraseEndLine,
-                 kw = key_width,
-                 vw = value_width);
-        println!("{:>kw$}: {:vw$} {:>kw$}: {:vw$} {:>kw$}: {:vw$}{}",
-                 "ipos", self.format_bytes_with_percentage(self.map_file.get_pos()),
-                 "rescued", self.get_histogram_value_formatted(SectorState::Rescued),
-                 "bad", self.get_histogram_value_formatted(SectorState::Bad),
-                 ansi_escapes::EraseEndLine,
-                 kw = key_width,
-                 vw = value_width);
-
-        println!("{:>kw$}: {:vw$} {:>kw$}: {:vw$} {:>kw$}: {:vw$}{}",
-                 "non-tried", self.get_histogram_value_formatted(SectorState::Untried),
-                 "non-trimmed", self.get_histogram_value_formatted(SectorState::Untrimmed),
-                 "non-scraped", self.get_histogram_value_formatted(SectorState::Unscraped),
-                 ansi_escapes::EraseEndLine,
-                 kw = key_width,
-                 vw = value_width);
-
-        let now = Instant::now();
-        let elapsed = now.duration_since(self.start).as_secs();
-
-        let good = self.stats.good;
-        let bad = self.stats.bad;
-        let total = self.stats.good + self.stats.bad;
-        let bytes_remaining = self.get_histogram_value(SectorState::Untried)
-            + self.get_histogram_value(SectorState::Untrimmed)
-            + self.get_histogram_value(SectorState::Unscraped);
-        let seconds_remaining = if total > 0 {
-            bytes_remaining * elapsed / total
-        } else {
-            0
-        };
-
-        println!("{:>kw$}: {:vw$} {:>kw$}: {:vw$} {:>kw$}: {:vw$}{}",
-                 "read rate", self.format_rate(good, elapsed),
-                 "error rate", self.format_rate(bad, elapsed),
-                 "total rate", self.format_rate(total, elapsed),
-                 ansi_escapes::EraseEndLine,
-                 kw = key_width,
-                 vw = value_width);
-
-        let last_success = match self.last_success {
-            None => String::from("never"),
-            Some(time) => format!("{} ago", self.format_seconds(now.duration_since(time).as_secs())),
-        };
-        println!("{:>kw$}: {:vw$} {:>kw$}: {:vw$} {:>kw$}: {:vw$}{}",
-                 "run time", self.format_seconds(elapsed),
-                 "last success", last_success,
-                 "remaining", self.format_seconds(seconds_remaining),
-                 ansi_escapes::EraseEndLine,
-                 kw = key_width,
-                 vw = value_width);
-        print!("{}{}", ansi_escapes::EraseEndLine, ansi_escapes::CursorLeft);
-    }
-
-    fn format_bytes(&self, bytes: u64) -> String {
-        let units = ["KiB", "MiB", "GiB"];
-        let mut res_unit = "B";
-        let mut res_bytes = bytes as f64;
-        for unit in units.iter() {
-            if res_bytes >= 1000000.0 {
-                res_bytes /= 1024.0;
-                res_unit = *unit;
-            }
-        }
-        format!("{:.0} {}", res_bytes, res_unit)
-    }
-
-    fn format_bytes_with_percentage(&self, bytes: u64) -> String {
-        let percentage = (bytes as f64) * 100.0 / (self.map_file.get_size() as f64);
-        format!("{} ({:.1}%)", self.format_bytes(bytes), percentage)
-    }
-
-    fn format_rate(&self, bytes: u64, seconds: u64) -> String {
-        if bytes == 0 || seconds > 0 {
-            let rate = if seconds > 0 {
-                bytes / seconds
-            } else {
-                0
-            };
-            format!("{}/s", self.format_bytes(rate))
-        } else {
-            String::from("inf")
-        }
-    }
-
-    fn format_seconds(&self, seconds: u64) -> String {
-        let mut value = seconds;
-        let mut elements = Vec::new();
-        for &(unit, multiple) in [("s", 60), ("m", 60), ("h", 24), ("d", usize::max_value())].iter() {
-            let multiple = multiple as u64;
-            elements.push(format!("{}{}", value % multiple, unit));
-            value /= multiple;
-
-            if value == 0 {
-                break;
-            }
-        }
-        let max_time_components = 2;
-        elements.reverse();
-        elements.truncate(max_time_components);
-        elements.join(" ")
-    }
-
-    fn get_histogram_value_formatted(&self, state: SectorState) -> String {
-        self.format_bytes_with_percentage(self.get_histogram_value(state))
-    }
-
-
-    fn get_histogram_value(&self, state: SectorState) -> u64 {
-        *self.histogram.get(&state).unwrap_or(&0)
-    }
-
-    fn update_histogram(&mut self, bytes: u64, from: SectorState, to: SectorState) {
-        *self.histogram.entry(from).or_insert(0) -= bytes;
-        *self.histogram.entry(to).or_insert(0) += bytes;
-    }
-
-    fn do_phase(&mut self) -> Result<(), Box<Error>> {
-        self.map_file.set_pass(1);
-        match self.map_file.get_phase().target_sectors() {
-            Some(phase_target) => {
-                while self.get_histogram_value(phase_target) > 0 && self.should_run() {
-                    self.do_pass(&phase_target)?;
-                    if self.is_pass_complete() {
-                        self.map_file.set_pos(0);
-                        self.map_file.next_pass();
-                    }
-                }
-            },
-            None => {},
-        }
-        Ok(())
-    }
-
-    fn do_phases(&mut self) -> Result<(), Box<Error>> {
-        self.update_status();
-        let mut finished = false;
-        while !finished && self.should_run() {
-            if self.is_phase_complete() {
-                let current_phase = self.map_file.get_phase();
-                match current_phase.next() {
-                    Some(phase) => {
-                        self.map_file.set_phase(&phase);
-                    },
-                    None => finished = true,
-                }
-            } else {
-                self.do_phase()?;
-            }
-        }
-        self.do_sync()?;
-        Ok(())
-    }
-
-    fn is_pass_complete(&self) -> bool {
-        let current_phase = self.map_file.get_phase();
-        match current_phase.target_sectors() {
-            Some(phase_target) => {
-                (&self.map_file).iter_range(self.map_file.get_pos()..self.map_file.get_size())
-                .filter(|r| r.tag == phase_target).next().is_none()
-            },
-            None => true,
-        }
-    }
-
-    fn is_phase_complete(&self) -> bool {
-        let current_phase = self.map_file.get_phase();
-        match current_phase.target_sectors() {
-            Some(phase_target) => {
-                (&self.map_file).iter_range(0..self.map_file.get_size())
-                .filter(|r| r.tag == phase_target).next().is_none()
-            },
-            None => true,
-        }
-    }
-
-    fn get_cleared_buffer(&mut self) -> Buffer {
-        let sectors_per_buffer = self.block.get_block_size_physical() / self.block.get_sector_size();
-        let mut buffer = match self.buffer_cache.pop() {
-            Some(buffer) => buffer,
-            None => self.block.create_io_buffer(sectors_per_buffer),
-        };
-        buffer.clear();
-        buffer
-    }
-
-    fn recycle_buffer(&mut self, buffer: Buffer) {
-        self.buffer_cache.push(buffer)
-    }
-
-    fn try_drain_request(&mut self, phase_target: &SectorState) -> Result<(), Box<Error>> {
-        if self.block.requests_pending() > 0 {
-            let request = match self.block.get_completed_request() {
-                Ok(r) => r,
-                Err(nix::Error::Sys(nix::Errno::EINTR)) => return Ok(()),
-                Err(err) => return Err(Box::new(err)),
-            };
-            self.stats.requests += 1;
-            if request.result > 0 {
-                let request_result = request.result as u64;
-                if !request.is_data_zeros() {
-                    self.out_file.seek(SeekFrom::Start(request.offset))?;
-                    self.out_file.write_all(request.get_data())?;
-                }
-                self.update_histogram(request_result, *phase_target, SectorState::Rescued);
-                self.map_file.put(request.offset..(request.offset + request_result), SectorState::Rescued);
-                self.last_success = Some(Instant::now());
-                self.stats.good += request_result;
-            } else {
-                self.update_histogram(request.size as u64, *phase_target, SectorState::Bad);
-                self.map_file.put(request.offset..(request.offset + request.size), SectorState::Bad);
-                self.stats.bad += request.size;
-            };
-            self.recycle_buffer(request.reclaim_buffer());
-        }
-        Ok(())
-    }
-
-    fn do_pass(&mut self, phase_target: &SectorState) -> Result<(), Box<Error>> {
-        let mut pass_complete = false;
-        while !pass_complete && self.should_run() {
-            let mut reads: VecDeque<Range<u64>> =
-                (&self.map_file).iter_range(self.map_file.get_pos()..self.map_file.get_size())
-                .filter(|r| r.tag == *phase_target)
-                .flat_map(|r| range_to_reads(&r.as_range(), &self.block))
-                .take(READ_BATCH_SIZE).collect();
-
-            pass_complete = reads.is_empty();
-            while !reads.is_empty() && self.should_run() {
-                if self.block.requests_avail() > 0 {
-                    let read = reads.pop_front().unwrap();
-                    let buffer = self.get_cleared_buffer();
-                    let request = Request::new(read.start, read.end - read.start, buffer);
-                    self.block.submit_request(request)?;
-                    let current_start = self.map_file.get_pos();
-                    self.map_file.set_pos(cmp::max(current_start, read.end));
-                }
-                if self.block.requests_avail() == 0 {
-                    self.try_drain_request(phase_target)?;
-                    self.update_status();
-                }
-                let now = Instant::now();
-                if now.duration_since(self.last_sync.clone()).as_secs() >= SYNC_INTERVAL as u64 {
-                    self.do_sync()?;
-                }
-            }
-        }
-        while self.block.requests_pending() > 0 {
-            self.try_drain_request(phase_target)?;
-            self.update_status();
-        }
-        Ok(())
-    }
-}
-
 fn print_usage(program: &str, opts: &Options) {
     println!("{}", opts.usage(&format!("Usage: {} -i input_device -o output_file -m map_file", program)));
 }
 
 fn main() {
-    do_work().unwrap();
+    let code = match do_work() {
+        Ok(()) => match signals::get_interrupting_signal() {
+            Some(signal) => EXIT_SIGNAL_BASE + signal as i32,
+            None => 0,
+        },
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            err.exit_code()
+        },
+    };
+    process::exit(code);
 }
 
-fn do_work() -> Result<(), Box<Error>> {
+fn do_work() -> Result<(), RecoverError> {
     let args : Vec<String> = env::args().collect();
     let program = &args[0];
 
     let mut opts = Options::new();
     opts.optflag("h", "help", "Show usage.");
-    opts.reqopt("i", "input", "Input device (required).", "FILE");
-    opts.reqopt("o", "output", "Output file (required).", "FILE");
-    opts.reqopt("m", "map", "Map file (required).", "FILE");
+    opts.optopt("i", "input", "Input device (required).", "FILE");
+    opts.optopt("o", "output", "Output file or block device (required).", "FILE");
+    opts.optopt("m", "map", "Map file (required).", "FILE");
+    opts.optopt("f", "format", "Format of a new output file: raw (default), compressed or qcow2.", "FORMAT");
+    opts.optopt("", "export-raw", "Export the compressed image given by --output to a raw image and exit.", "FILE");
+    opts.optopt("", "extract-map", "Extract the map embedded in the compressed image given by --output and exit.", "FILE");
+    opts.optflag("", "no-sparse", "Write zero-filled areas to the output instead of leaving holes.");
+    opts.optflag("", "direct", "Write to the output using O_DIRECT.");
+    opts.optflag("", "force", "Write to an output block device even if it is in use.");
+    opts.optflag("", "preallocate", "Reserve space for the entire output file before starting (implies --no-sparse).");
+    opts.optflag("", "async-writes", "Write to the output asynchronously (most effective with --direct).");
+    opts.optflag("", "hash", "Compute SHA-256 digests of the image as it is rescued.");
+    opts.optopt("", "confirm-reads", "Outside the copying phase, only consider data rescued once N reads agree (default 1).", "N");
+    opts.optopt("", "vote-reads", "When confirmation reads disagree, reconstruct the data by majority vote over N reads (implies --confirm-reads 2 if not given).", "N");
+    opts.optopt("", "status-format", "Format of status updates: text (default on a terminal), plain (default otherwise), json, or tui for a full-screen display with a map of the device.", "FORMAT");
+    opts.optopt("", "progress-interval", "Seconds between status updates (default 0.5, or 30 for plain updates).", "SECONDS");
+    opts.optflag("q", "quiet", "Only report phase changes and the final summary.");
+    opts.optopt("", "log-rates", "Append the position, read rates and bad areas to FILE every second.", "FILE");
+    opts.optopt("", "log-reads", "Append the result and latency of every read to FILE.", "FILE");
+    opts.optflag("", "log-events", "Log changes of phase, syncs, bad areas and interruptions alongside the map file.");
+    opts.optflag("", "latency-map", "Record how long reads of each region take in a map alongside the map file.");
+    opts.optopt("", "metrics-port", "Serve Prometheus metrics over HTTP on PORT of the loopback interface.", "PORT");
+    opts.optopt("", "control-socket", "Accept commands to pause, resume or adjust the rescue on a Unix domain socket at PATH.", "PATH");
+    opts.optflag("", "verify", "Compare rescued regions of the output with the input device and exit.");
+    opts.optflag("", "verify-reset", "Verify as with --verify, marking regions which differ as non-tried.");
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => { m }
         Err(e) => {
-            println!("Error: {}", e.description());
             print_usage(&program, &opts);
-            return Ok(())
+            return Err(RecoverError::Usage(format!("{}.", e.to_string().trim_end_matches('.'))));
         },
     };
 
-    let free_args = &matches.free;
-    let needed_args = 0;
-    if matches.opt_present("h") || free_args.len() != needed_args {
+    if matches.opt_present("h") {
         print_usage(&program, &opts);
         return Ok(());
     }
+    if let Some(arg) = matches.free.first() {
+        print_usage(&program, &opts);
+        return Err(RecoverError::Usage(format!("Unexpected argument '{}'.", arg)));
+    }
 
-    let input = matches.opt_str("i").unwrap();
-    let output = matches.opt_str("o").unwrap();
-    let map = matches.opt_str("m").unwrap();
+    let output = match matches.opt_str("o") {
+        Some(output) => output,
+        None => {
+            print_usage(&program, &opts);
+            return Err(RecoverError::Usage(String::from("Required option 'output' missing.")));
+        },
+    };
+    if let Some(raw_path) = matches.opt_str("export-raw") {
+        let mut image = CompressedImage::open_existing(Path::new(&output)).map_err(RecoverError::output)?;
+        image.export_raw(Path::new(&raw_path))?;
+        return Ok(());
+    }
+    if let Some(map_path) = matches.opt_str("extract-map") {
+        if !CompressedImage::is_compressed_image(Path::new(&output)).map_err(RecoverError::output)? {
+            return Err(RecoverError::Usage(String::from("--extract-map can only be used with a compressed image.")));
+        }
+        let mut image = CompressedImage::open_existing(Path::new(&output)).map_err(RecoverError::output)?;
+        match image.read_embedded_map().map_err(RecoverError::output)? {
+            Some(data) => File::create(map_path)?.write_all(&data)?,
+            None => return Err(RecoverError::map(PathBuf::from(&output), "No map is embedded in the image")),
+        }
+        return Ok(());
+    }
 
-    let mut recover = Recover::new(input.as_str(), output.as_str(), map.as_str())?;
-    recover.do_phases()?;
-    Ok(())
-}
+    let (input, map) = match (matches.opt_str("i"), matches.opt_str("m")) {
+        (Some(input), Some(map)) => (input, map),
+        _ => {
+            print_usage(&program, &opts);
+            return Err(RecoverError::Usage(String::from("Options 'input' and 'map' are required.")));
+        },
+    };
+    let format = match matches.opt_str("f") {
+        Some(name) => match OutputFormat::from_name(&name) {
+            Some(format) => Some(format),
+            None => {
+                print_usage(&program, &opts);
+                return Err(RecoverError::Usage(format!("Unknown output format '{}'.", name)));
+            },
+        },
+        None => None,
+    };
+    let confirm_reads = match matches.opt_str("confirm-reads") {
+        Some(value) => match value.parse::<usize>() {
+            Ok(count) if count >= 1 => count,
+            _ => {
+                print_usage(&program, &opts);
+                return Err(RecoverError::Usage(format!("Invalid number of confirmation reads '{}'.", value)));
+            },
+        },
+        None => 1,
+    };
+    let vote_reads = match matches.opt_str("vote-reads") {
+        Some(value) => match value.parse::<usize>() {
+            Ok(count) if count >= 3 => count,
+            _ => {
+                print_usage(&program, &opts);
+                return Err(RecoverError::Usage(format!("Invalid number of reads to vote on '{}' (at least 3 are required).", value)));
+            },
+        },
+        None => 0,
+    };
+    let confirm_reads = if vote_reads > 0 { cmp::max(confirm_reads, 2) } else { confirm_reads };
+    // Redrawing the display in place only makes sense on a terminal
+    let is_terminal = nix::unistd::isatty(io::stdout().as_raw_fd()).unwrap_or(false);
+    let status_format = match matches.opt_str("status-format").as_ref().map(|f| f.as_str()) {
+        None if is_terminal && !matches.opt_present("quiet") => StatusFormat::Text,
+        None => StatusFormat::Plain,
+        Some("text") => StatusFormat::Text,
+        Some("plain") => StatusFormat::Plain,
+        Some("json") => StatusFormat::Json,
+        Some("tui") if is_terminal => StatusFormat::Tui,
+        Some("tui") => {
+            return Err(RecoverError::Usage(String::from("The tui status format requires a terminal.")));
+        },
+        Some(name) => {
+            print_usage(&program, &opts);
+            return Err(RecoverError::Usage(format!("Unknown status format '{}'.", name)));
+        },
+    };
+    let progress_interval = match matches.opt_str("progress-interval") {
+        Some(value) => match value.parse::<f32>() {
+            Ok(seconds) if seconds >= 0.0 => seconds,
+            _ => {
+                print_usage(&program, &opts);
+                return Err(RecoverError::Usage(format!("Invalid progress interval '{}'.", value)));
+            },
+        },
+        None if status_format == StatusFormat::Plain => recover::PLAIN_REFRESH_INTERVAL,
+        None => recover::REFRESH_INTERVAL,
+    };
+    let metrics_port = match matches.opt_str("metrics-port") {
+        Some(value) => match value.parse::<u16>() {
+            Ok(port) => Some(port),
+            Err(_) => {
+                print_usage(&program, &opts);
+                return Err(RecoverError::Usage(format!("Invalid metrics port '{}'.", value)));
+            },
+        },
+        None => None,
+    };
+    let settings = Settings {
+        sparse: !matches.opt_present("no-sparse"),
+        direct: matches.opt_present("direct"),
+        force: matches.opt_present("force"),
+        preallocate: matches.opt_present("preallocate"),
+        async_writes: matches.opt_present("async-writes"),
+        hash: matches.opt_present("hash"),
+        verify: matches.opt_present("verify") || matches.opt_present("verify-reset"),
+        verify_reset: matches.opt_present("verify-reset"),
+        confirm_reads: confirm_reads,
+        vote_reads: vote_reads,
+        status_format: status_format,
+        progress_interval: progress_interval,
+        quiet: matches.opt_present("quiet"),
+        log_rates: matches.opt_str("log-rates").map(PathBuf::from),
+        log_reads: matches.opt_str("log-reads").map(PathBuf::from),
+        log_events: matches.opt_present("log-events"),
+        latency_map: matches.opt_present("latency-map"),
+        metrics_port: metrics_port,
+        control_socket: matches.opt_str("control-socket").map(PathBuf::from),
+    };
 
-struct ReadIter {
-    start: u64,
-    end: u64,
-    physical_block_size: usize,
+    let output_path = Path::new(&output);
+    let format = match format {
+        Some(format) => format,
+        None if output_path.exists() && CompressedImage::is_compressed_image(output_path).map_err(RecoverError::output)? => OutputFormat::Compressed,
+        None if output_path.exists() && Qcow2Image::is_qcow2_image(output_path).map_err(RecoverError::output)? => OutputFormat::Qcow2,
+        None => OutputFormat::Raw,
+    };
+    check_output_options(&settings, format, output_path)?;
+    let block = BlockDevice::open(input.as_str()).map_err(|e| RecoverError::device(&input, e))?;
+    match format {
+        OutputFormat::Raw => {
+            let mut outfile_options = OutFileOptions::new();
+            outfile_options.sparse = settings.sparse;
+            outfile_options.direct = settings.direct;
+            outfile_options.force = settings.force;
+            outfile_options.preallocate = settings.preallocate;
+            let outfile = OutFile::open(output_path, block.get_size_bytes(), &outfile_options).map_err(RecoverError::output)?;
+            match outfile.get_direct_alignment().map_err(RecoverError::output)? {
+                Some(alignment) if block.get_sector_size() % alignment != 0 => {
+                    let message = format!("Direct I/O to the output requires an alignment of {} bytes, which is incompatible with the input sector size ({})",
+                                          alignment, block.get_sector_size());
+                    return Err(RecoverError::GeometryMismatch(message));
+                },
+                _ => {},
+            }
+            recover(block, outfile, &map, settings)
+        },
+        OutputFormat::Compressed => {
+            let image = CompressedImage::open(output_path, block.get_size_bytes()).map_err(RecoverError::output)?;
+            recover(block, image, &map, settings)
+        },
+        OutputFormat::Qcow2 => {
+            let image = Qcow2Image::open(output_path, block.get_size_bytes()).map_err(RecoverError::output)?;
+            recover(block, image, &map, settings)
+        },
+    }
 }
 
-impl Iterator for ReadIter {
-    type Item = Range<u64>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        let physical_block_size = self.physical_block_size as u64;
-        if self.start < self.end {
-            let read_end = cmp::min(((self.start + physical_block_size) / physical_block_size) * physical_block_size, self.end);
-            let result = self.start..read_end;
-            self.start = read_end;
-            Some(result)
-        } else {
-            None
-        }
+// Rejects the options for raw output files which would have no effect on the output given
+fn check_output_options(settings: &Settings, format: OutputFormat, output_path: &Path) -> Result<(), RecoverError> {
+    let is_block_device = fs::metadata(output_path).map(|m| m.file_type().is_block_device()).unwrap_or(false);
+    let mut unsupported = vec![("--preallocate", settings.preallocate)];
+    let kind = match format {
+        // Block devices are never written sparsely, so --no-sparse is accepted for them
+        OutputFormat::Raw if is_block_device => "a block device",
+        OutputFormat::Raw => return Ok(()),
+        OutputFormat::Compressed => "a compressed",
+        OutputFormat::Qcow2 => "a qcow2",
+    };
+    if format != OutputFormat::Raw {
+        // Images are written through their own containers rather than to a file descriptor
+        unsupported.push(("--no-sparse", !settings.sparse));
+        unsupported.push(("--direct", settings.direct));
+        unsupported.push(("--async-writes", settings.async_writes));
+    }
+    match unsupported.iter().find(|&&(_, given)| given) {
+        Some(&(option, _)) => Err(RecoverError::Usage(format!("{} cannot be used with {} output.", option, kind))),
+        None => Ok(()),
     }
 }
 
-fn range_to_reads(range: &Range<u64>, block: &BlockDevice) -> ReadIter {
-    let sector_size = block.get_sector_size();
-    let physical_block_size = block.get_block_size_physical();
-    let size_bytes = block.get_size_bytes();
-    assert!(physical_block_size % sector_size == 0);
-
-    let sector_size_u64 = sector_size as u64;
-    let start = (range.start / sector_size_u64) * sector_size_u64;
-    let end = cmp::min(((range.end + sector_size_u64 - 1) / sector_size_u64) * sector_size_u64, size_bytes);
-    ReadIter {
-        start: start,
-        end: end,
-        physical_block_size: physical_block_size,
+fn recover<S: Sink>(block: BlockDevice, output: S, map: &str, settings: Settings) -> Result<(), RecoverError> {
+    let verify = settings.verify;
+    let mut recover = Recover::new(block, output, map, settings)?;
+    signals::install().map_err(|e| RecoverError::Other(Box::new(e)))?;
+    if verify {
+        recover.do_verify()?;
+        return Ok(());
     }
+    recover.run_phases()?;
+    recover.print_summary();
+    Ok(())
 }
@@ -1,8 +1,10 @@
+use atomic_file;
+use error::MismatchError;
 use parse_error::ParseError;
 use phase::Phase;
 use std::cmp;
 use std::collections::HashMap;
-use std::fs::{self, OpenOptions};
+use std::fs::File;
 use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
 use std::ops::Range;
 use std::path::Path;
@@ -37,7 +39,7 @@ impl SectorState {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct MapFile {
     pos: u64,
     status: Phase,
@@ -53,23 +55,79 @@ impl MapFile {
         for region in self.sector_states.into_iter() {
             writeln!(&mut write, "0x{:08X}  0x{:08X}  {}", region.start, region.length, region.tag.as_char())?;
         }
-        Ok(())
+        write.flush()
     }
 
     pub fn write_to_path(&self, path: &Path) -> io::Result<()> {
-        let mut tmp_path = path.to_path_buf();
-        tmp_path.set_extension("ddarescue-tmp");
-        {
-            let mut file = OpenOptions::new()
-                    .create_new(true)
-                    .write(true)
-                    .open(&tmp_path)?;
-            self.write_to_stream(&mut file)?;
-            file.flush()?;
-            file.sync_all()?;
+        atomic_file::replace(path, true, |file| self.write_to_stream(file))
+    }
+
+    // Reads the first generation of the map which parses and describes a device of `size_bytes`,
+    // along with a warning explaining why if it is not the map itself. Returns `None` if no
+    // generation exists.
+    //
+    // The map itself is used whenever it is valid. Otherwise a temporary left behind by an
+    // interrupted write is preferred to the backup, as it is only left behind once it has been
+    // written completely, before the backup is replaced. Modification times are not compared, as
+    // a backup which had to be copied rather than linked is newer than the map it was copied from.
+    pub fn read_first_valid(path: &Path, size_bytes: u64) -> Result<Option<(MapFile, Option<String>)>, Box<Error>> {
+        let candidates = [
+            path.to_path_buf(),
+            atomic_file::generation_path(path, atomic_file::TMP_SUFFIX),
+            atomic_file::generation_path(path, atomic_file::BACKUP_SUFFIX),
+        ];
+        let mut primary_error = None;
+        let mut first_error = None;
+        for (index, candidate) in candidates.iter().enumerate().filter(|&(_, p)| p.exists()) {
+            let result = File::open(candidate).map_err(|e| Box::new(e) as Box<Error>)
+                .and_then(|file| Self::read_validated(file, size_bytes));
+            match result {
+                Ok(map) if index == 0 => return Ok(Some((map, None))),
+                Ok(map) => {
+                    let reason = match primary_error {
+                        Some(ref err) => format!("{} could not be used: {}", path.display(), err),
+                        None => format!("{} does not exist", path.display()),
+                    };
+                    let warning = format!("Using {} because {}.", candidate.display(), reason);
+                    return Ok(Some((map, Some(warning))));
+                },
+                Err(err) => {
+                    if index == 0 {
+                        primary_error = Some(err.to_string());
+                    }
+                    if first_error.is_none() {
+                        first_error = Some(err);
+                    }
+                },
+            }
+        }
+        match first_error {
+            Some(err) => Err(err),
+            None => Ok(None),
+        }
+    }
+
+    // Reads a map, checking that it describes the whole of a device of `size_bytes`.
+    pub fn read_validated<R>(read: R, size_bytes: u64) -> Result<MapFile, Box<Error>> where R: Read {
+        let map = Self::read_from_stream(read)?;
+        if map.get_size_bytes() != size_bytes {
+            return Err(Box::new(MismatchError::new("Size of map file does not match device")));
+        }
+        if !map.is_contiguous() {
+            return Err(Box::new(ParseError::new("map file with gaps between regions")));
+        }
+        Ok(map)
+    }
+
+    fn is_contiguous(&self) -> bool {
+        let mut end = 0;
+        for region in self.sector_states.iter() {
+            if region.start != end {
+                return false;
+            }
+            end = region.start + region.length;
         }
-        fs::rename(tmp_path, path)?;
-        Ok(())
+        end == self.size_bytes
     }
 
     pub fn get_size_bytes(&self) -> u64 {
@@ -132,6 +190,10 @@ impl MapFile {
             }
         }
 
+        if !read_state {
+            return Err(Box::new(ParseError::new("map file status line")));
+        }
+
         let result = MapFile {
             pos: pos.unwrap(),
             status: status.unwrap(),
@@ -212,3 +274,109 @@ impl<'a> IntoIterator for &'a MapFile {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atomic_file;
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
+    use testing::test_dir;
+
+    const SIZE: u64 = 0x100000;
+
+    fn write_map(path: &Path, rescued: u64) {
+        let mut map = MapFile::new(SIZE);
+        map.put(0..rescued, SectorState::Rescued);
+        map.write_to_stream(File::create(path).unwrap()).unwrap();
+    }
+
+    fn rescued(map: &MapFile) -> u64 {
+        *map.get_histogram().get(&SectorState::Rescued).unwrap_or(&0)
+    }
+
+    // Ensures that the next file written has a later modification time
+    fn tick() {
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    #[test]
+    fn stale_temp_is_ignored() {
+        let dir = test_dir("map-stale-temp");
+        let path = dir.join("drive.map");
+        write_map(&path, 0x1000);
+        tick();
+        fs::write(atomic_file::generation_path(&path, atomic_file::TMP_SUFFIX), "0x00000000     ?").unwrap();
+
+        let (map, warning) = MapFile::read_first_valid(&path, SIZE).unwrap().unwrap();
+        assert_eq!(rescued(&map), 0x1000);
+        assert_eq!(warning, None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_primary_falls_back_to_backup() {
+        let dir = test_dir("map-corrupt-primary");
+        let path = dir.join("drive.map");
+        write_map(&atomic_file::generation_path(&path, atomic_file::BACKUP_SUFFIX), 0x2000);
+        tick();
+        fs::write(&path, "not a map\n").unwrap();
+
+        let (map, warning) = MapFile::read_first_valid(&path, SIZE).unwrap().unwrap();
+        assert_eq!(rescued(&map), 0x2000);
+        let warning = warning.unwrap();
+        assert!(warning.contains("drive.map.bak"), "{}", warning);
+        assert!(warning.contains("could not be used"), "{}", warning);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // The generations left by a crash just after the backup was copied, on a filesystem without
+    // hard links: the backup is the newest file, but holds the same map as the map itself
+    fn write_copied_backup(path: &Path) {
+        write_map(&atomic_file::generation_path(path, atomic_file::TMP_SUFFIX), 0x3000);
+        tick();
+        fs::copy(path, atomic_file::generation_path(path, atomic_file::BACKUP_SUFFIX)).unwrap();
+    }
+
+    #[test]
+    fn copied_backup_does_not_override_map() {
+        let dir = test_dir("map-copied-backup");
+        let path = dir.join("drive.map");
+        write_map(&path, 0x1000);
+        tick();
+        write_copied_backup(&path);
+
+        let (map, warning) = MapFile::read_first_valid(&path, SIZE).unwrap().unwrap();
+        assert_eq!(rescued(&map), 0x1000);
+        assert_eq!(warning, None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_map_falls_back_to_temp_before_copied_backup() {
+        let dir = test_dir("map-missing-map");
+        let path = dir.join("drive.map");
+        write_map(&path, 0x1000);
+        tick();
+        write_copied_backup(&path);
+        fs::remove_file(&path).unwrap();
+
+        let (map, warning) = MapFile::read_first_valid(&path, SIZE).unwrap().unwrap();
+        assert_eq!(rescued(&map), 0x3000);
+        let warning = warning.unwrap();
+        assert!(warning.contains("drive.map.ddarescue-tmp"), "{}", warning);
+        assert!(warning.contains("does not exist"), "{}", warning);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn no_valid_generation_is_an_error() {
+        let dir = test_dir("map-no-valid");
+        let path = dir.join("drive.map");
+        assert!(MapFile::read_first_valid(&path, SIZE).unwrap().is_none());
+        write_map(&path, 0x1000);
+        assert!(MapFile::read_first_valid(&path, SIZE * 2).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,184 @@
+use latency_map::{self, BUCKET_COUNT, BUCKET_LIMITS_MS};
+use std::fmt::{self, Write as FmtWrite};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, SocketAddrV4, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use status::Status;
+
+// Serves the progress of a rescue as Prometheus metrics over HTTP on the loopback interface.
+//
+// Requests are answered from a separate thread with the most recent snapshot of the metrics, so
+// that scrapes succeed even while the rescue is blocked waiting for a slow read. Snapshots record
+// when they were taken and when the last successful read happened as timestamps, which remain
+// meaningful however old the snapshot is.
+
+const READ_TIMEOUT: u64 = 5;
+
+#[derive(Debug)]
+pub struct MetricsServer {
+    snapshot: Arc<Mutex<String>>,
+}
+
+impl MetricsServer {
+    pub fn start(port: u16) -> io::Result<MetricsServer> {
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port))?;
+        let snapshot = Arc::new(Mutex::new(String::new()));
+        let shared = snapshot.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if let Ok(stream) = stream {
+                    // A failed response only affects the client which requested it
+                    let _ = respond(stream, &shared);
+                }
+            }
+        });
+        Ok(MetricsServer {
+            snapshot: snapshot,
+        })
+    }
+
+    pub fn update(&self, metrics: String) {
+        *self.snapshot.lock().unwrap() = metrics;
+    }
+}
+
+fn respond(stream: TcpStream, snapshot: &Mutex<String>) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT)))?;
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers are of no interest, but must be read before responding
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+    let mut stream = reader.into_inner();
+    let fields: Vec<&str> = request_line.split_whitespace().collect();
+    let (status, body) = match (fields.get(0), fields.get(1)) {
+        (Some(&"GET"), Some(&"/")) | (Some(&"GET"), Some(&"/metrics")) => ("200 OK", snapshot.lock().unwrap().clone()),
+        (Some(&"GET"), _) => ("404 Not Found", String::from("Not found\n")),
+        _ => ("405 Method Not Allowed", String::from("Method not allowed\n")),
+    };
+    write!(stream, "HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+           status, body.len(), body)?;
+    stream.flush()
+}
+
+// Counts of completed reads by how long they took, in the form of a Prometheus histogram
+#[derive(Clone, Debug)]
+pub struct LatencyHistogram {
+    counts: [u64; BUCKET_COUNT],
+    sum_seconds: f64,
+}
+
+impl LatencyHistogram {
+    pub fn new() -> LatencyHistogram {
+        LatencyHistogram {
+            counts: [0; BUCKET_COUNT],
+            sum_seconds: 0.0,
+        }
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        self.counts[latency_map::bucket(latency) as usize] += 1;
+        self.sum_seconds += latency.as_secs() as f64 + latency.subsec_nanos() as f64 * 1e-9;
+    }
+}
+
+// Everything reported beyond the status itself
+#[derive(Debug)]
+pub struct Extra<'a> {
+    pub queue_depth: usize,
+    pub queue_capacity: usize,
+    pub read_latency: &'a LatencyHistogram,
+    // Bytes in each bucket of the latency map, if there is one
+    pub latency_map: Option<[u64; BUCKET_COUNT]>,
+}
+
+struct Metrics {
+    text: String,
+}
+
+impl Metrics {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(&mut self.text, "# HELP ddarecover_{} {}", name, help).unwrap();
+        writeln!(&mut self.text, "# TYPE ddarecover_{} {}", name, kind).unwrap();
+    }
+
+    fn sample<T: fmt::Display>(&mut self, name: &str, labels: &[(&str, &str)], value: T) {
+        let labels: Vec<String> = labels.iter().map(|&(k, v)| format!("{}=\"{}\"", k, escape_label(v))).collect();
+        if labels.is_empty() {
+            writeln!(&mut self.text, "ddarecover_{} {}", name, value).unwrap();
+        } else {
+            writeln!(&mut self.text, "ddarecover_{}{{{}}} {}", name, labels.join(","), value).unwrap();
+        }
+    }
+
+    fn single<T: fmt::Display>(&mut self, name: &str, kind: &str, help: &str, value: T) {
+        self.family(name, kind, help);
+        self.sample(name, &[], value);
+    }
+}
+
+pub fn render(status: &Status, extra: &Extra) -> String {
+    let mut m = Metrics {
+        text: String::new(),
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0)).as_secs();
+
+    m.family("phase", "gauge", "Current phase of the rescue.");
+    m.sample("phase", &[("phase", &status.phase.name())], 1);
+    m.single("pass", "gauge", "Current pass within the phase.", status.pass);
+    m.single("position_bytes", "gauge", "Current read position.", status.pos);
+    m.single("device_size_bytes", "gauge", "Size of the input device.", status.size);
+
+    m.family("region_bytes", "gauge", "Bytes of the device in each state of the map.");
+    for &(state, bytes) in [("rescued", status.rescued), ("non_tried", status.untried), ("non_trimmed", status.untrimmed),
+                            ("non_scraped", status.unscraped), ("bad", status.bad)].iter() {
+        m.sample("region_bytes", &[("state", state)], bytes);
+    }
+
+    m.single("read_bytes_total", "counter", "Bytes read successfully during this run.", status.read_bytes);
+    m.single("error_bytes_total", "counter", "Bytes which failed to read during this run.", status.error_bytes);
+    m.single("unstable_bytes_total", "counter", "Bytes found to read differently on rereading.", status.unstable);
+    m.single("reconstructed_bytes_total", "counter", "Bytes reconstructed by majority vote.", status.reconstructed);
+    m.single("queue_depth", "gauge", "Requests submitted to the device and not yet completed.", extra.queue_depth);
+    m.single("queue_capacity", "gauge", "Maximum number of requests in flight.", extra.queue_capacity);
+    m.single("run_time_seconds", "gauge", "Time since this run started.", status.elapsed);
+    m.single("remaining_seconds", "gauge", "Estimated time until the rescue finishes.", status.remaining_seconds());
+    m.single("last_update_timestamp_seconds", "gauge", "When these metrics were last updated.", now);
+    // Until a read succeeds the start of the run stands in, so that a rescue which is stuck from
+    // the outset still appears to have made no progress for longer and longer
+    let since_success = status.since_success.unwrap_or(status.elapsed);
+    m.single("last_success_timestamp_seconds", "gauge",
+             "When the last successful read completed, or this run started if none has.", now - since_success);
+
+    m.family("read_latency_seconds", "histogram", "Time taken by the device to complete reads during this run.");
+    let mut cumulative = 0;
+    for (bucket, count) in extra.read_latency.counts.iter().enumerate() {
+        cumulative += *count;
+        let le = match BUCKET_LIMITS_MS.get(bucket) {
+            Some(limit) => format!("{}", *limit as f64 / 1000.0),
+            None => String::from("+Inf"),
+        };
+        m.sample("read_latency_seconds_bucket", &[("le", &le)], cumulative);
+    }
+    m.sample("read_latency_seconds_sum", &[], extra.read_latency.sum_seconds);
+    m.sample("read_latency_seconds_count", &[], cumulative);
+
+    if let Some(ref histogram) = extra.latency_map {
+        m.family("latency_map_bytes", "gauge", "Bytes of the device by the latency of their most recent read.");
+        for (bucket, bytes) in histogram.iter().enumerate() {
+            m.sample("latency_map_bytes", &[("latency", &latency_map::bucket_name(bucket as u8))], *bytes);
+        }
+    }
+    m.text
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
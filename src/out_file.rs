@@ -1,23 +1,115 @@
+use block::{BlockDevice, Buffer};
+use error::MismatchError;
+use libc;
+use nix;
+use num::cast;
+use sink::Sink;
+use statx_abi;
 use std::cmp;
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::ops::Range;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::Path;
 
+const ZERO_BLOCK_SIZE: usize = 65536;
+// Sufficient for O_DIRECT on any device or filesystem we are likely to encounter
+const DIRECT_ALIGNMENT: usize = 4096;
+
+#[derive(Clone, Debug)]
+pub struct OutFileOptions {
+    // Leave holes for zero-filled areas rather than writing zeros
+    pub sparse: bool,
+    // Bypass the page cache using O_DIRECT
+    pub direct: bool,
+    // Write to an output block device even if it is in use, e.g. by a mounted filesystem
+    pub force: bool,
+    // Reserve space for the entire output file when it is opened
+    pub preallocate: bool,
+}
+
+impl OutFileOptions {
+    pub fn new() -> OutFileOptions {
+        OutFileOptions {
+            sparse: true,
+            direct: false,
+            force: false,
+            preallocate: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct DeviceGeometry {
+    sector_size: usize,
+    size_bytes: u64,
+}
+
 #[derive(Debug)]
 pub struct OutFile {
     file: File,
+    options: OutFileOptions,
+    punch_supported: bool,
+    device: Option<DeviceGeometry>,
+    zeros: Buffer,
 }
 
 impl OutFile {
-    pub fn open(path: &Path, size_bytes: u64) -> io::Result<OutFile> {
-        let file = if !path.exists() {
+    pub fn open(path: &Path, size_bytes: u64, options: &OutFileOptions) -> io::Result<OutFile> {
+        let is_block_device = match fs::metadata(path) {
+            Ok(meta) => meta.file_type().is_block_device(),
+            Err(_) => false,
+        };
+        let mut options = options.clone();
+        let mut custom_flags = 0;
+        if options.direct {
+            custom_flags |= libc::O_DIRECT;
+        }
+
+        if is_block_device {
+            if !options.force {
+                // Without O_CREAT, O_EXCL on a block device fails with EBUSY if it is mounted,
+                // has mounted partitions or is otherwise claimed.
+                custom_flags |= libc::O_EXCL;
+            }
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(custom_flags)
+                .open(path)
+                .map_err(|e| if e.raw_os_error() == Some(libc::EBUSY) {
+                    io::Error::new(e.kind(), "Output device is in use (possibly mounted)")
+                } else {
+                    e
+                })?;
+            let fd = file.as_raw_fd();
+            let device = DeviceGeometry {
+                sector_size: cast::<u32, usize>(BlockDevice::query_sector_size(fd).map_err(nix_to_io)?).unwrap(),
+                size_bytes: BlockDevice::query_size_bytes(fd).map_err(nix_to_io)?,
+            };
+            if device.size_bytes < size_bytes {
+                let message = format!("Output device is too small ({} bytes required, {} available)",
+                                      size_bytes, device.size_bytes);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+            }
+            // Holes cannot be punched in a device, and its previous contents are unknown
+            options.sparse = false;
+            return Ok(Self::from_parts(file, options, Some(device)));
+        }
+
+        let created = !path.exists();
+        let file = if created {
             let file = OpenOptions::new()
                 .create_new(true)
                 .read(true)
                 .write(true)
+                .custom_flags(custom_flags)
                 .open(path)?;
-            file.set_len(size_bytes)?;
+            if let Err(err) = file.set_len(size_bytes) {
+                let _ = fs::remove_file(path);
+                return Err(err);
+            }
             file
         } else {
             OpenOptions::new()
@@ -25,18 +117,153 @@ impl OutFile {
                 .write(true)
                 .create(false)
                 .truncate(false)
+                .custom_flags(custom_flags)
                 .open(path)?
         };
 
         let meta = file.metadata()?;
         if meta.len() != size_bytes {
-            panic!("Output file size does not match required length");
+            return Err(MismatchError::new("Output file size does not match required length").into_io_error());
+        }
+
+        if options.preallocate {
+            if let Err(err) = Self::preallocate(&file, size_bytes) {
+                if created {
+                    let _ = fs::remove_file(path);
+                }
+                return Err(err);
+            }
+            // Punching holes would release the reserved space
+            options.sparse = false;
+        }
+        Ok(Self::from_parts(file, options, None))
+    }
+
+    fn preallocate(file: &File, size_bytes: u64) -> io::Result<()> {
+        let length = cast::<u64, libc::off_t>(size_bytes).unwrap();
+        if unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, length) } == -1 {
+            let err = io::Error::last_os_error();
+            let message = match err.raw_os_error() {
+                Some(libc::ENOSPC) => format!("Insufficient space to preallocate {} bytes of output", size_bytes),
+                Some(libc::EOPNOTSUPP) => String::from("Output filesystem does not support preallocation"),
+                _ => return Err(err),
+            };
+            Err(io::Error::new(err.kind(), message))
+        } else {
+            Ok(())
         }
+    }
 
-        let res = OutFile {
+    fn from_parts(file: File, options: OutFileOptions, device: Option<DeviceGeometry>) -> OutFile {
+        let mut zeros = Buffer::allocate_aligned(ZERO_BLOCK_SIZE, DIRECT_ALIGNMENT);
+        zeros.clear();
+        OutFile {
             file: file,
+            options: options,
+            punch_supported: true,
+            device: device,
+            zeros: zeros,
+        }
+    }
+
+    pub fn is_block_device(&self) -> bool {
+        self.device.is_some()
+    }
+
+    // Logical sector size of an output block device
+    pub fn get_sector_size(&self) -> Option<usize> {
+        self.device.as_ref().map(|d| d.sector_size)
+    }
+
+    pub fn is_sparse(&self) -> bool {
+        self.options.sparse
+    }
+
+    // The alignment of offsets and buffers required for writing to the output with O_DIRECT: the
+    // logical sector size of a device, or what the filesystem of a file reports
+    pub fn get_direct_alignment(&self) -> io::Result<Option<usize>> {
+        if !self.options.direct {
+            return Ok(None);
+        }
+        if let Some(ref device) = self.device {
+            return Ok(Some(device.sector_size));
+        }
+        let mut stat = statx_abi::statx::new();
+        let res = unsafe {
+            statx_abi::statx(self.file.as_raw_fd(), b"\0".as_ptr() as *const libc::c_char,
+                             statx_abi::AT_EMPTY_PATH, statx_abi::STATX_DIOALIGN, &mut stat)
         };
-        Ok(res)
+        if res == 0 && stat.stx_mask & statx_abi::STATX_DIOALIGN != 0 {
+            if stat.stx_dio_offset_align == 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Output filesystem does not support direct I/O"));
+            }
+            let alignment = cmp::max(stat.stx_dio_offset_align, stat.stx_dio_mem_align);
+            return Ok(Some(cast::<u32, usize>(alignment).unwrap()));
+        }
+        // Older kernels do not report the alignment, but the preferred I/O size is a multiple of it
+        Ok(Some(cast::<u64, usize>(self.file.metadata()?.blksize()).unwrap()))
+    }
+
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.write_all(data)
+    }
+
+    pub fn read_at(&mut self, offset: u64, data: &mut [u8]) -> io::Result<()> {
+        self.seek(SeekFrom::Start(offset))?;
+        self.read_exact(data)
+    }
+
+    // In sparse mode, a hole is punched so that any data previously in the range is discarded.
+    // Punching a hole where one already exists is cheap, so the range is not inspected first
+    // unless the filesystem cannot punch holes.
+    pub fn write_zeros(&mut self, range: Range<u64>) -> io::Result<()> {
+        if self.options.sparse {
+            if self.punch_supported {
+                match self.punch_hole(range.clone()) {
+                    Ok(()) => return Ok(()),
+                    Err(ref e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => self.punch_supported = false,
+                    Err(e) => return Err(e),
+                }
+            }
+            if self.is_range_zero(range.clone())? {
+                return Ok(());
+            }
+        }
+        self.file.seek(SeekFrom::Start(range.start))?;
+        let mut remaining = range.end - range.start;
+        while remaining > 0 {
+            let write_size = cmp::min(remaining, self.zeros.len() as u64);
+            self.file.write_all(&self.zeros.as_slice()[0..write_size as usize])?;
+            remaining -= write_size;
+        }
+        Ok(())
+    }
+
+    fn punch_hole(&mut self, range: Range<u64>) -> io::Result<()> {
+        let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+        let offset = cast::<u64, libc::off_t>(range.start).unwrap();
+        let length = cast::<u64, libc::off_t>(range.end - range.start).unwrap();
+        if unsafe { libc::fallocate(self.file.as_raw_fd(), mode, offset, length) } == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn get_apparent_size_bytes(&self) -> io::Result<u64> {
+        match self.device {
+            Some(ref device) => Ok(device.size_bytes),
+            None => Ok(self.file.metadata()?.len()),
+        }
+    }
+
+    pub fn get_allocated_bytes(&self) -> io::Result<u64> {
+        match self.device {
+            Some(ref device) => Ok(device.size_bytes),
+            // st_blocks is always in units of 512 bytes, regardless of the filesystem block size
+            None => Ok(self.file.metadata()?.blocks() * 512),
+        }
     }
 
     pub fn sync(&mut self) -> io::Result<()> {
@@ -45,13 +272,13 @@ impl OutFile {
     }
 
     pub fn is_range_zero(&mut self, range: Range<u64>) -> io::Result<bool> {
-        let mut data = vec![0u8; 65536];
+        let mut data = Buffer::allocate_aligned(ZERO_BLOCK_SIZE, DIRECT_ALIGNMENT);
         self.seek(SeekFrom::Start(range.start))?;
         let mut remaining = range.end - range.start;
         while remaining > 0 {
             let read_size = cmp::min(remaining, data.len() as u64);
-            let data = &mut data[0..read_size as usize];
-            self.read_exact(&mut data[..])?;
+            let data = &mut data.as_mut_slice()[0..read_size as usize];
+            self.file.read_exact(&mut data[..])?;
             for value in &data[..] {
                 if *value != 0 {
                     return Ok(false);
@@ -63,6 +290,44 @@ impl OutFile {
     }
 }
 
+impl Sink for OutFile {
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        OutFile::write_at(self, offset, data)
+    }
+
+    fn write_zeros(&mut self, range: Range<u64>) -> io::Result<()> {
+        OutFile::write_zeros(self, range)
+    }
+
+    fn read_at(&mut self, offset: u64, data: &mut [u8]) -> io::Result<()> {
+        OutFile::read_at(self, offset, data)
+    }
+
+    fn is_range_zero(&mut self, range: Range<u64>) -> io::Result<bool> {
+        OutFile::is_range_zero(self, range)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        OutFile::sync(self)
+    }
+
+    fn get_size_bytes(&self) -> io::Result<u64> {
+        self.get_apparent_size_bytes()
+    }
+
+    fn get_allocated_bytes(&self) -> io::Result<u64> {
+        OutFile::get_allocated_bytes(self)
+    }
+
+    fn is_sparse(&self) -> bool {
+        OutFile::is_sparse(self)
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
 impl Read for OutFile {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         self.file.read(buf)
@@ -85,8 +350,22 @@ impl Seek for OutFile {
     }
 }
 
+impl AsRawFd for OutFile {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
 impl Drop for OutFile {
     fn drop(&mut self) {
-        self.sync().unwrap()
+        // Any error would already have been reported by the final sync of the rescue
+        let _ = self.sync();
+    }
+}
+
+fn nix_to_io(err: nix::Error) -> io::Error {
+    match err {
+        nix::Error::Sys(errno) => errno.into(),
+        err => io::Error::new(io::ErrorKind::Other, err),
     }
 }
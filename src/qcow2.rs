@@ -0,0 +1,765 @@
+use error::MismatchError;
+use sink::Sink;
+use std::cmp;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+// A writer for qcow2 images (see docs/interop/qcow2.txt in the QEMU source). Clusters are only
+// allocated for non-zero data, since unallocated clusters read as zeros. Images without backing
+// files, encryption, compression, snapshots or extended L2 entries can be reopened to resume a
+// rescue.
+//
+// Metadata is cached in memory and written on sync, after the data it refers to is durable. A
+// crash may therefore leak clusters, but never leaves metadata referring to unwritten data.
+
+const MAGIC: u32 = 0x5146_49fb;
+const VERSION: u32 = 3;
+const HEADER_LENGTH: u32 = 104;
+const CLUSTER_BITS: u32 = 16;
+const CLUSTER_SIZE: u64 = 1 << CLUSTER_BITS;
+// Each refcount is 16 bits wide
+const REFCOUNT_ORDER: u32 = 4;
+const REFCOUNTS_PER_BLOCK: u64 = CLUSTER_SIZE / 2;
+const L2_ENTRIES: u64 = CLUSTER_SIZE / 8;
+
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const FLAG_COPIED: u64 = 1 << 63;
+const FLAG_COMPRESSED: u64 = 1 << 62;
+const FLAG_ZERO: u64 = 1;
+
+#[derive(Debug)]
+pub struct Qcow2Image {
+    file: File,
+    size_bytes: u64,
+    l1_table: Vec<u64>,
+    l1_table_offset: u64,
+    l1_dirty: bool,
+    l2_tables: HashMap<u64, Vec<u64>>,
+    dirty_l2_tables: BTreeSet<u64>,
+    refcount_table: Vec<u64>,
+    refcount_table_offset: u64,
+    refcount_table_dirty: bool,
+    refcount_blocks: HashMap<u64, Vec<u16>>,
+    dirty_refcount_blocks: BTreeSet<u64>,
+    next_free: u64,
+}
+
+impl Qcow2Image {
+    pub fn is_qcow2_image(path: &Path) -> io::Result<bool> {
+        let mut magic = [0u8; 4];
+        let mut file = File::open(path)?;
+        match file.read_exact(&mut magic) {
+            Ok(()) => Ok(get_u32(&magic) == MAGIC),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn open(path: &Path, size_bytes: u64) -> io::Result<Qcow2Image> {
+        if path.exists() {
+            let image = Self::open_existing(path)?;
+            if image.size_bytes != size_bytes {
+                return Err(MismatchError::new("qcow2 image size does not match required length").into_io_error());
+            }
+            Ok(image)
+        } else {
+            Self::create(path, size_bytes)
+        }
+    }
+
+    fn l1_size(size_bytes: u64) -> u64 {
+        let clusters = (size_bytes + CLUSTER_SIZE - 1) / CLUSTER_SIZE;
+        (clusters + L2_ENTRIES - 1) / L2_ENTRIES
+    }
+
+    // The refcount table is never grown, so it is sized for the largest file the image could
+    // require: every data cluster and L2 table allocated, plus the refcount blocks themselves.
+    fn refcount_table_clusters(size_bytes: u64) -> u64 {
+        let l1_size = Self::l1_size(size_bytes);
+        let fixed_clusters = (size_bytes + CLUSTER_SIZE - 1) / CLUSTER_SIZE + l1_size
+            + 1 + clusters_for_bytes(l1_size * 8);
+        let mut table_clusters = 1;
+        loop {
+            let mut blocks = 0;
+            loop {
+                let total_clusters = fixed_clusters + table_clusters + blocks;
+                let required = (total_clusters + REFCOUNTS_PER_BLOCK - 1) / REFCOUNTS_PER_BLOCK;
+                if required <= blocks {
+                    break;
+                }
+                blocks = required;
+            }
+            let required = clusters_for_bytes(blocks * 8);
+            if required <= table_clusters {
+                return table_clusters;
+            }
+            table_clusters = required;
+        }
+    }
+
+    pub fn create(path: &Path, size_bytes: u64) -> io::Result<Qcow2Image> {
+        let file = OpenOptions::new()
+            .create_new(true)
+            .read(true)
+            .write(true)
+            .open(path)?;
+        let l1_size = Self::l1_size(size_bytes);
+        let refcount_table_clusters = Self::refcount_table_clusters(size_bytes);
+        let refcount_table_offset = CLUSTER_SIZE;
+        let l1_table_offset = refcount_table_offset + refcount_table_clusters * CLUSTER_SIZE;
+        let next_free = l1_table_offset + clusters_for_bytes(l1_size * 8) * CLUSTER_SIZE;
+
+        let mut image = Qcow2Image {
+            file: file,
+            size_bytes: size_bytes,
+            l1_table: vec![0; l1_size as usize],
+            l1_table_offset: l1_table_offset,
+            l1_dirty: true,
+            l2_tables: HashMap::new(),
+            dirty_l2_tables: BTreeSet::new(),
+            refcount_table: vec![0; (refcount_table_clusters * CLUSTER_SIZE / 8) as usize],
+            refcount_table_offset: refcount_table_offset,
+            refcount_table_dirty: true,
+            refcount_blocks: HashMap::new(),
+            dirty_refcount_blocks: BTreeSet::new(),
+            next_free: next_free,
+        };
+        let mut offset = 0;
+        while offset < next_free {
+            image.increment_refcount(offset)?;
+            offset += CLUSTER_SIZE;
+        }
+
+        let mut header = vec![0u8; CLUSTER_SIZE as usize];
+        put_u32(&mut header[0..4], MAGIC);
+        put_u32(&mut header[4..8], VERSION);
+        put_u32(&mut header[20..24], CLUSTER_BITS);
+        put_u64(&mut header[24..32], size_bytes);
+        put_u32(&mut header[36..40], l1_size as u32);
+        put_u64(&mut header[40..48], l1_table_offset);
+        put_u64(&mut header[48..56], refcount_table_offset);
+        put_u32(&mut header[56..60], refcount_table_clusters as u32);
+        put_u32(&mut header[96..100], REFCOUNT_ORDER);
+        put_u32(&mut header[100..104], HEADER_LENGTH);
+        // The header extension area is terminated by a zero-filled end marker, which follows.
+        image.file.seek(SeekFrom::Start(0))?;
+        image.file.write_all(&header)?;
+        image.sync()?;
+        Ok(image)
+    }
+
+    pub fn open_existing(path: &Path) -> io::Result<Qcow2Image> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)?;
+        let mut header = [0u8; HEADER_LENGTH as usize];
+        file.read_exact(&mut header[0..72])?;
+        if get_u32(&header[0..4]) != MAGIC {
+            return Err(invalid_data("Not a qcow2 image"));
+        }
+        let version = get_u32(&header[4..8]);
+        if version == 3 {
+            file.read_exact(&mut header[72..104])?;
+            if get_u64(&header[72..80]) != 0 {
+                return Err(invalid_data("qcow2 image uses unsupported incompatible features"));
+            }
+            if get_u32(&header[96..100]) != REFCOUNT_ORDER {
+                return Err(invalid_data("Unsupported qcow2 refcount width"));
+            }
+        } else if version != 2 {
+            return Err(invalid_data("Unsupported qcow2 version"));
+        }
+        if get_u64(&header[8..16]) != 0 {
+            return Err(invalid_data("qcow2 images with backing files are not supported"));
+        }
+        if get_u32(&header[20..24]) != CLUSTER_BITS {
+            return Err(invalid_data("Unsupported qcow2 cluster size"));
+        }
+        if get_u32(&header[32..36]) != 0 {
+            return Err(invalid_data("Encrypted qcow2 images are not supported"));
+        }
+        if get_u32(&header[60..64]) != 0 {
+            return Err(invalid_data("qcow2 images with snapshots are not supported"));
+        }
+        let size_bytes = get_u64(&header[24..32]);
+        let l1_size = get_u32(&header[36..40]) as u64;
+        let l1_table_offset = get_u64(&header[40..48]);
+        let refcount_table_offset = get_u64(&header[48..56]);
+        let refcount_table_clusters = get_u32(&header[56..60]) as u64;
+        if l1_size < Self::l1_size(size_bytes) {
+            return Err(invalid_data("qcow2 L1 table is too small"));
+        }
+        if refcount_table_clusters < Self::refcount_table_clusters(size_bytes) {
+            return Err(invalid_data("qcow2 refcount table is too small to complete image"));
+        }
+
+        let l1_table = read_u64_table(&mut file, l1_table_offset, l1_size)?;
+        let refcount_table = read_u64_table(&mut file, refcount_table_offset, refcount_table_clusters * CLUSTER_SIZE / 8)?;
+        let next_free = clusters_for_bytes(file.metadata()?.len()) * CLUSTER_SIZE;
+        Ok(Qcow2Image {
+            file: file,
+            size_bytes: size_bytes,
+            l1_table: l1_table,
+            l1_table_offset: l1_table_offset,
+            l1_dirty: false,
+            l2_tables: HashMap::new(),
+            dirty_l2_tables: BTreeSet::new(),
+            refcount_table: refcount_table,
+            refcount_table_offset: refcount_table_offset,
+            refcount_table_dirty: false,
+            refcount_blocks: HashMap::new(),
+            dirty_refcount_blocks: BTreeSet::new(),
+            next_free: next_free,
+        })
+    }
+
+    pub fn get_size_bytes(&self) -> u64 {
+        self.size_bytes
+    }
+
+    pub fn get_allocated_bytes(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.blocks() * 512)
+    }
+
+    fn allocate_cluster(&mut self) -> io::Result<u64> {
+        let offset = self.next_free;
+        self.next_free += CLUSTER_SIZE;
+        self.increment_refcount(offset)?;
+        Ok(offset)
+    }
+
+    fn increment_refcount(&mut self, offset: u64) -> io::Result<()> {
+        let cluster = offset / CLUSTER_SIZE;
+        let block = cluster / REFCOUNTS_PER_BLOCK;
+        if block as usize >= self.refcount_table.len() {
+            return Err(invalid_data("qcow2 refcount table is full"));
+        }
+        if self.refcount_table[block as usize] == 0 {
+            let block_offset = self.next_free;
+            self.next_free += CLUSTER_SIZE;
+            self.refcount_table[block as usize] = block_offset;
+            self.refcount_table_dirty = true;
+            self.refcount_blocks.insert(block, vec![0; REFCOUNTS_PER_BLOCK as usize]);
+            self.dirty_refcount_blocks.insert(block);
+            // The new refcount block needs a reference too, usually from itself
+            self.increment_refcount(block_offset)?;
+        }
+        let refcounts = self.refcount_block(block)?;
+        let index = (cluster % REFCOUNTS_PER_BLOCK) as usize;
+        refcounts[index] = refcounts[index].checked_add(1).ok_or_else(|| invalid_data("qcow2 refcount overflow"))?;
+        self.dirty_refcount_blocks.insert(block);
+        Ok(())
+    }
+
+    fn refcount_block(&mut self, block: u64) -> io::Result<&mut Vec<u16>> {
+        if !self.refcount_blocks.contains_key(&block) {
+            let offset = self.refcount_table[block as usize];
+            let mut bytes = vec![0u8; CLUSTER_SIZE as usize];
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.file.read_exact(&mut bytes)?;
+            let refcounts = bytes.chunks(2).map(|b| (b[0] as u16) << 8 | b[1] as u16).collect();
+            self.refcount_blocks.insert(block, refcounts);
+        }
+        Ok(self.refcount_blocks.get_mut(&block).unwrap())
+    }
+
+    fn l2_table(&mut self, l1_index: u64) -> io::Result<Option<&mut Vec<u64>>> {
+        let entry = self.l1_table[l1_index as usize];
+        if entry & OFFSET_MASK == 0 {
+            return Ok(None);
+        }
+        if !self.l2_tables.contains_key(&l1_index) {
+            let table = read_u64_table(&mut self.file, entry & OFFSET_MASK, L2_ENTRIES)?;
+            self.l2_tables.insert(l1_index, table);
+        }
+        Ok(self.l2_tables.get_mut(&l1_index))
+    }
+
+    // Returns the host offset of a virtual cluster, or None if it reads as zeros.
+    fn lookup_cluster(&mut self, cluster: u64) -> io::Result<Option<u64>> {
+        let entry = match self.l2_table(cluster / L2_ENTRIES)? {
+            Some(table) => table[(cluster % L2_ENTRIES) as usize],
+            None => return Ok(None),
+        };
+        if entry & FLAG_COMPRESSED != 0 {
+            return Err(invalid_data("Compressed qcow2 clusters are not supported"));
+        }
+        if entry & FLAG_ZERO != 0 || entry & OFFSET_MASK == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(entry & OFFSET_MASK))
+        }
+    }
+
+    fn allocate_data_cluster(&mut self, cluster: u64) -> io::Result<u64> {
+        let l1_index = cluster / L2_ENTRIES;
+        if self.l1_table[l1_index as usize] & OFFSET_MASK == 0 {
+            let table_offset = self.allocate_cluster()?;
+            self.l1_table[l1_index as usize] = table_offset | FLAG_COPIED;
+            self.l1_dirty = true;
+            self.l2_tables.insert(l1_index, vec![0; L2_ENTRIES as usize]);
+            self.dirty_l2_tables.insert(l1_index);
+        }
+        let previous = self.l2_table(l1_index)?.unwrap()[(cluster % L2_ENTRIES) as usize];
+        if previous & FLAG_COPIED == 0 && previous & OFFSET_MASK != 0 {
+            return Err(invalid_data("Shared qcow2 clusters are not supported"));
+        }
+        let offset = if previous & OFFSET_MASK != 0 {
+            // A preallocated cluster with the zero flag set
+            self.write_zero_bytes(previous & OFFSET_MASK, CLUSTER_SIZE)?;
+            previous & OFFSET_MASK
+        } else {
+            self.allocate_cluster()?
+        };
+        self.l2_table(l1_index)?.unwrap()[(cluster % L2_ENTRIES) as usize] = offset | FLAG_COPIED;
+        self.dirty_l2_tables.insert(l1_index);
+        Ok(offset)
+    }
+
+    fn write_zero_bytes(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        let zeros = vec![0u8; length as usize];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(&zeros)
+    }
+
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let end = offset + data.len() as u64;
+        assert!(end <= self.size_bytes, "Write beyond end of qcow2 image");
+        let mut pos = offset;
+        while pos < end {
+            let cluster = pos / CLUSTER_SIZE;
+            let length = cmp::min(end, (cluster + 1) * CLUSTER_SIZE) - pos;
+            let source = &data[((pos - offset) as usize)..][..(length as usize)];
+            let host_offset = match self.lookup_cluster(cluster)? {
+                Some(host_offset) => Some(host_offset),
+                None if source.iter().all(|v| *v == 0) => None,
+                None => Some(self.allocate_data_cluster(cluster)?),
+            };
+            if let Some(host_offset) = host_offset {
+                self.file.seek(SeekFrom::Start(host_offset + pos % CLUSTER_SIZE))?;
+                self.file.write_all(source)?;
+            }
+            pos += length;
+        }
+        Ok(())
+    }
+
+    pub fn write_zeros(&mut self, range: Range<u64>) -> io::Result<()> {
+        assert!(range.end <= self.size_bytes, "Write beyond end of qcow2 image");
+        let mut pos = range.start;
+        while pos < range.end {
+            let cluster = pos / CLUSTER_SIZE;
+            let length = cmp::min(range.end, (cluster + 1) * CLUSTER_SIZE) - pos;
+            if let Some(host_offset) = self.lookup_cluster(cluster)? {
+                self.write_zero_bytes(host_offset + pos % CLUSTER_SIZE, length)?;
+            }
+            pos += length;
+        }
+        Ok(())
+    }
+
+    pub fn read_at(&mut self, offset: u64, data: &mut [u8]) -> io::Result<()> {
+        let end = offset + data.len() as u64;
+        assert!(end <= self.size_bytes, "Read beyond end of qcow2 image");
+        let mut pos = offset;
+        while pos < end {
+            let cluster = pos / CLUSTER_SIZE;
+            let length = cmp::min(end, (cluster + 1) * CLUSTER_SIZE) - pos;
+            let target = &mut data[((pos - offset) as usize)..][..(length as usize)];
+            match self.lookup_cluster(cluster)? {
+                Some(host_offset) => {
+                    self.file.seek(SeekFrom::Start(host_offset + pos % CLUSTER_SIZE))?;
+                    self.file.read_exact(target)?;
+                },
+                None => for value in target.iter_mut() {
+                    *value = 0;
+                },
+            }
+            pos += length;
+        }
+        Ok(())
+    }
+
+    pub fn is_range_zero(&mut self, range: Range<u64>) -> io::Result<bool> {
+        let mut pos = range.start;
+        while pos < range.end {
+            let cluster = pos / CLUSTER_SIZE;
+            let end = cmp::min(range.end, (cluster + 1) * CLUSTER_SIZE);
+            if self.lookup_cluster(cluster)?.is_some() {
+                let mut data = vec![0u8; (end - pos) as usize];
+                self.read_at(pos, &mut data)?;
+                if data.iter().any(|v| *v != 0) {
+                    return Ok(false);
+                }
+            }
+            pos = end;
+        }
+        Ok(true)
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        // Ensure every allocated cluster lies within the file
+        if self.file.metadata()?.len() < self.next_free {
+            self.file.set_len(self.next_free)?;
+        }
+        self.file.sync_all()?;
+
+        for block in self.dirty_refcount_blocks.iter() {
+            let mut bytes = Vec::with_capacity(CLUSTER_SIZE as usize);
+            for refcount in self.refcount_blocks[block].iter() {
+                bytes.push((*refcount >> 8) as u8);
+                bytes.push(*refcount as u8);
+            }
+            self.file.seek(SeekFrom::Start(self.refcount_table[*block as usize]))?;
+            self.file.write_all(&bytes)?;
+        }
+        for l1_index in self.dirty_l2_tables.iter() {
+            let offset = self.l1_table[*l1_index as usize] & OFFSET_MASK;
+            write_u64_table(&mut self.file, offset, &self.l2_tables[l1_index])?;
+        }
+        self.file.sync_all()?;
+
+        if self.refcount_table_dirty {
+            write_u64_table(&mut self.file, self.refcount_table_offset, &self.refcount_table)?;
+        }
+        if self.l1_dirty {
+            write_u64_table(&mut self.file, self.l1_table_offset, &self.l1_table)?;
+        }
+        self.file.sync_all()?;
+
+        // Metadata is reloaded on demand, which bounds memory use for large images
+        self.refcount_blocks.clear();
+        self.dirty_refcount_blocks.clear();
+        self.l2_tables.clear();
+        self.dirty_l2_tables.clear();
+        self.refcount_table_dirty = false;
+        self.l1_dirty = false;
+        Ok(())
+    }
+}
+
+impl Sink for Qcow2Image {
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        Qcow2Image::write_at(self, offset, data)
+    }
+
+    fn write_zeros(&mut self, range: Range<u64>) -> io::Result<()> {
+        Qcow2Image::write_zeros(self, range)
+    }
+
+    fn read_at(&mut self, offset: u64, data: &mut [u8]) -> io::Result<()> {
+        Qcow2Image::read_at(self, offset, data)
+    }
+
+    fn is_range_zero(&mut self, range: Range<u64>) -> io::Result<bool> {
+        Qcow2Image::is_range_zero(self, range)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Qcow2Image::sync(self)
+    }
+
+    fn get_size_bytes(&self) -> io::Result<u64> {
+        Ok(Qcow2Image::get_size_bytes(self))
+    }
+
+    fn get_allocated_bytes(&self) -> io::Result<u64> {
+        Qcow2Image::get_allocated_bytes(self)
+    }
+}
+
+impl Drop for Qcow2Image {
+    fn drop(&mut self) {
+        // Errors have no way out of a destructor; a rescue syncs before it finishes
+        let _ = self.sync();
+    }
+}
+
+fn clusters_for_bytes(bytes: u64) -> u64 {
+    (bytes + CLUSTER_SIZE - 1) / CLUSTER_SIZE
+}
+
+fn read_u64_table(file: &mut File, offset: u64, entries: u64) -> io::Result<Vec<u64>> {
+    let mut bytes = vec![0u8; (entries * 8) as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut bytes)?;
+    Ok(bytes.chunks(8).map(get_u64).collect())
+}
+
+fn write_u64_table(file: &mut File, offset: u64, table: &[u64]) -> io::Result<()> {
+    let mut bytes = vec![0u8; table.len() * 8];
+    for (entry, value) in bytes.chunks_mut(8).zip(table.iter()) {
+        put_u64(entry, *value);
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(&bytes)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn put_u32(bytes: &mut [u8], value: u32) {
+    for (idx, byte) in bytes[0..4].iter_mut().enumerate() {
+        *byte = (value >> ((3 - idx) * 8)) as u8;
+    }
+}
+
+fn put_u64(bytes: &mut [u8], value: u64) {
+    for (idx, byte) in bytes[0..8].iter_mut().enumerate() {
+        *byte = (value >> ((7 - idx) * 8)) as u8;
+    }
+}
+
+fn get_u32(bytes: &[u8]) -> u32 {
+    bytes[0..4].iter().fold(0, |value, byte| value << 8 | *byte as u32)
+}
+
+fn get_u64(bytes: &[u8]) -> u64 {
+    bytes[0..8].iter().fold(0, |value, byte| value << 8 | *byte as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use testing::noise;
+
+    fn test_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("ddarecover-qcow2-{}-{}", process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn read_range(image: &mut Qcow2Image, range: Range<u64>) -> Vec<u8> {
+        let mut data = vec![0u8; (range.end - range.start) as usize];
+        image.read_at(range.start, &mut data).unwrap();
+        data
+    }
+
+    // Checks that every cluster in the file is referenced exactly once by the metadata and has a
+    // refcount of one, and that every other refcount is zero, as qemu-img check would.
+    fn check_metadata(path: &Path) {
+        let mut image = Qcow2Image::open_existing(path).unwrap();
+        let file_clusters = clusters_for_bytes(fs::metadata(path).unwrap().len());
+        let mut used = vec![0u16; file_clusters as usize];
+        {
+            let mut mark = |offset: u64| {
+                assert_eq!(offset % CLUSTER_SIZE, 0);
+                used[(offset / CLUSTER_SIZE) as usize] += 1;
+            };
+            mark(0);
+            for idx in 0..(image.refcount_table.len() as u64 * 8 / CLUSTER_SIZE) {
+                mark(image.refcount_table_offset + idx * CLUSTER_SIZE);
+            }
+            for idx in 0..clusters_for_bytes(image.l1_table.len() as u64 * 8) {
+                mark(image.l1_table_offset + idx * CLUSTER_SIZE);
+            }
+            for entry in image.refcount_table.iter().filter(|entry| **entry != 0) {
+                mark(*entry);
+            }
+            for l1_index in 0..(image.l1_table.len() as u64) {
+                let entry = image.l1_table[l1_index as usize];
+                if entry == 0 {
+                    continue;
+                }
+                assert_eq!(entry & !OFFSET_MASK, FLAG_COPIED);
+                mark(entry & OFFSET_MASK);
+                for entry in image.l2_table(l1_index).unwrap().unwrap().iter().filter(|entry| **entry != 0) {
+                    assert_eq!(entry & !OFFSET_MASK, FLAG_COPIED);
+                    mark(entry & OFFSET_MASK);
+                }
+            }
+        }
+        for cluster in 0..file_clusters {
+            let block = cluster / REFCOUNTS_PER_BLOCK;
+            let refcount = if image.refcount_table[block as usize] == 0 {
+                0
+            } else {
+                image.refcount_block(block).unwrap()[(cluster % REFCOUNTS_PER_BLOCK) as usize]
+            };
+            assert_eq!(refcount, used[cluster as usize], "Refcount of cluster {}", cluster);
+            assert!(used[cluster as usize] <= 1, "Cluster {} is referenced more than once", cluster);
+        }
+    }
+
+    #[test]
+    fn create_writes_valid_header() {
+        let path = test_path("header");
+        let size = 3 * CLUSTER_SIZE + 12345;
+        drop(Qcow2Image::create(&path, size).unwrap());
+        let mut header = [0u8; HEADER_LENGTH as usize];
+        File::open(&path).unwrap().read_exact(&mut header).unwrap();
+        assert_eq!(get_u32(&header[0..4]), MAGIC);
+        assert_eq!(get_u32(&header[4..8]), VERSION);
+        assert_eq!(get_u32(&header[20..24]), CLUSTER_BITS);
+        assert_eq!(get_u64(&header[24..32]), size);
+        assert_eq!(get_u32(&header[36..40]), 1);
+        assert_eq!(get_u32(&header[96..100]), REFCOUNT_ORDER);
+        assert_eq!(get_u32(&header[100..104]), HEADER_LENGTH);
+        assert!(Qcow2Image::is_qcow2_image(&path).unwrap());
+
+        let image = Qcow2Image::open_existing(&path).unwrap();
+        assert_eq!(image.get_size_bytes(), size);
+        assert_eq!(image.l1_table, vec![0]);
+        drop(image);
+        check_metadata(&path);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn round_trip_and_reopen() {
+        let path = test_path("round-trip");
+        let size = 20 * CLUSTER_SIZE as usize + 12345;
+        let mut expected = vec![0u8; size];
+        {
+            let mut image = Qcow2Image::create(&path, size as u64).unwrap();
+            let offsets = noise(1, 100);
+            for (idx, value) in offsets.iter().enumerate() {
+                let offset = (*value as usize * 5231 + idx * 97) % size;
+                let length = cmp::min(1000 + idx * 1013, size - offset);
+                if idx % 4 == 3 {
+                    image.write_zeros(offset as u64..(offset + length) as u64).unwrap();
+                    for byte in &mut expected[offset..(offset + length)] {
+                        *byte = 0;
+                    }
+                } else {
+                    let data = noise(idx as u64, length);
+                    image.write_at(offset as u64, &data).unwrap();
+                    expected[offset..(offset + length)].copy_from_slice(&data);
+                }
+                if idx % 25 == 0 {
+                    image.sync().unwrap();
+                }
+            }
+            assert!(read_range(&mut image, 0..size as u64) == expected);
+        }
+        check_metadata(&path);
+
+        // Resume writing, both into allocated clusters and into new ones
+        {
+            let mut image = Qcow2Image::open(&path, size as u64).unwrap();
+            assert!(read_range(&mut image, 0..size as u64) == expected);
+            let data = noise(2, 3 * CLUSTER_SIZE as usize);
+            let offset = size - data.len() - 100;
+            image.write_at(offset as u64, &data).unwrap();
+            expected[offset..(offset + data.len())].copy_from_slice(&data);
+        }
+        check_metadata(&path);
+
+        let mut image = Qcow2Image::open_existing(&path).unwrap();
+        assert!(read_range(&mut image, 0..size as u64) == expected);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn writes_span_several_l2_tables() {
+        let path = test_path("l2-tables");
+        let l2_span = L2_ENTRIES * CLUSTER_SIZE;
+        let size = 2 * l2_span + 12345;
+        let data = noise(3, 3 * CLUSTER_SIZE as usize);
+        let offsets = [0, l2_span - CLUSTER_SIZE - 100, 2 * l2_span - 6 * CLUSTER_SIZE, size - data.len() as u64];
+        {
+            let mut image = Qcow2Image::create(&path, size).unwrap();
+            assert_eq!(image.l1_table.len(), 3);
+            for offset in offsets.iter() {
+                image.write_at(*offset, &data).unwrap();
+            }
+        }
+        check_metadata(&path);
+
+        let mut image = Qcow2Image::open_existing(&path).unwrap();
+        assert!(image.l1_table.iter().all(|entry| *entry != 0));
+        for offset in offsets.iter() {
+            assert!(read_range(&mut image, *offset..(*offset + data.len() as u64)) == data);
+        }
+        assert!(image.is_range_zero((3 * CLUSTER_SIZE)..(l2_span - CLUSTER_SIZE - 100)).unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unallocated_clusters_read_as_zeros() {
+        let path = test_path("zeros");
+        let size = 8 * CLUSTER_SIZE;
+        let mut image = Qcow2Image::create(&path, size).unwrap();
+        let empty_length = fs::metadata(&path).unwrap().len();
+        assert!(read_range(&mut image, 1000..(3 * CLUSTER_SIZE)).iter().all(|v| *v == 0));
+        assert!(image.is_range_zero(0..size).unwrap());
+
+        // Zeros never allocate clusters, whichever way they are written
+        image.write_at(CLUSTER_SIZE, &vec![0u8; 2 * CLUSTER_SIZE as usize]).unwrap();
+        image.write_zeros((4 * CLUSTER_SIZE)..(6 * CLUSTER_SIZE)).unwrap();
+        image.sync().unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), empty_length);
+
+        let data = noise(4, 1000);
+        image.write_at(2 * CLUSTER_SIZE + 500, &data).unwrap();
+        image.sync().unwrap();
+        assert!(fs::metadata(&path).unwrap().len() > empty_length);
+        assert!(!image.is_range_zero(0..size).unwrap());
+        let cluster = read_range(&mut image, (2 * CLUSTER_SIZE)..(3 * CLUSTER_SIZE));
+        assert!(cluster[..500].iter().all(|v| *v == 0));
+        assert!(cluster[500..1500] == data[..]);
+        assert!(cluster[1500..].iter().all(|v| *v == 0));
+
+        image.write_zeros((2 * CLUSTER_SIZE)..(3 * CLUSTER_SIZE)).unwrap();
+        assert!(image.is_range_zero(0..size).unwrap());
+        drop(image);
+        check_metadata(&path);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn size_must_match_on_reopen() {
+        let path = test_path("size");
+        Qcow2Image::create(&path, 100000).unwrap();
+        assert!(Qcow2Image::open(&path, 100000).is_ok());
+        assert!(Qcow2Image::open(&path, 200000).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn corrupt_headers_are_rejected() {
+        let path = test_path("corrupt");
+        let corruptions: &[(u64, &[u8])] = &[
+            // Magic
+            (0, b"QFI\0"),
+            // Version
+            (4, &[0, 0, 0, 4]),
+            // Backing file offset
+            (8, &[0, 0, 0, 0, 0, 0, 1, 0]),
+            // Cluster bits
+            (20, &[0, 0, 0, 12]),
+            // Encryption method
+            (32, &[0, 0, 0, 1]),
+            // L1 size
+            (36, &[0, 0, 0, 0]),
+            // Number of snapshots
+            (60, &[0, 0, 0, 1]),
+            // Incompatible features
+            (72, &[0, 0, 0, 0, 0, 0, 0, 1]),
+        ];
+        for &(offset, bytes) in corruptions.iter() {
+            let _ = fs::remove_file(&path);
+            drop(Qcow2Image::create(&path, 100000).unwrap());
+            let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            file.write_all(bytes).unwrap();
+            let error = Qcow2Image::open_existing(&path).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "Corruption at offset {}", offset);
+        }
+
+        fs::write(&path, b"QFI").unwrap();
+        assert!(!Qcow2Image::is_qcow2_image(&path).unwrap());
+        assert!(Qcow2Image::open_existing(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
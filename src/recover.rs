@@ -0,0 +1,1656 @@
+use ansi_escapes;
+use atomic_file;
+use block::{Buffer, Operation, Request};
+use control::{Command, ControlSocket};
+use error::RecoverError;
+use image_hash::{self, ImageHash};
+use input::Input;
+use latency_map::LatencyMap;
+use logs::{self, EventLog, RatesLog, ReadsLog};
+use map_file::{MapFile, SectorState};
+use metrics::{self, LatencyHistogram, MetricsServer};
+use phase::Phase;
+use sha256;
+use signals;
+use sink::Sink;
+use status::{self, JsonValue, Status, VerifyStatus};
+use tagged_range::TaggedRange;
+use tui::Tui;
+use unstable_log::UnstableLog;
+use vote;
+use std::cmp;
+use std::collections::{VecDeque, HashMap};
+use std::error::Error;
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+// The rescue itself: reading the input into the output phase by phase, keeping the map up to date,
+// and reporting progress.
+
+const READ_BATCH_SIZE: usize = 128;
+const SYNC_INTERVAL: usize = 5 * 60;
+// Default seconds between status updates, for displays redrawn in place and for plain progress
+// lines
+pub const REFRESH_INTERVAL: f32 = 0.5;
+pub const PLAIN_REFRESH_INTERVAL: f32 = 30.0;
+const METRICS_INTERVAL: u64 = 1;
+const PAUSE_POLL_INTERVAL_MS: u64 = 100;
+const RECONSTRUCTED_SUFFIX: &'static str = "reconstructed";
+
+#[derive(Debug)]
+struct Stats {
+    good: u64,
+    bad: u64,
+    requests: u64,
+    unstable: u64,
+    reconstructed: u64,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            good: 0,
+            bad: 0,
+            requests: 0,
+            unstable: 0,
+            reconstructed: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StatusFormat {
+    // A display which is redrawn in place, for terminals
+    Text,
+    // A line of progress at each refresh, for logs
+    Plain,
+    Json,
+    // A full-screen display with a map of the device
+    Tui,
+}
+
+#[derive(Debug)]
+pub struct Settings {
+    pub sparse: bool,
+    pub direct: bool,
+    pub force: bool,
+    pub preallocate: bool,
+    pub async_writes: bool,
+    pub hash: bool,
+    pub verify: bool,
+    pub verify_reset: bool,
+    // Number of agreeing reads required outside the copying phase
+    pub confirm_reads: usize,
+    // Number of reads to vote on once reads disagree, or zero to mark such ranges as bad
+    pub vote_reads: usize,
+    pub status_format: StatusFormat,
+    // Seconds between status updates
+    pub progress_interval: f32,
+    // Only report phase changes and the final summary
+    pub quiet: bool,
+    pub log_rates: Option<PathBuf>,
+    pub log_reads: Option<PathBuf>,
+    pub log_events: bool,
+    pub latency_map: bool,
+    pub metrics_port: Option<u16>,
+    pub control_socket: Option<PathBuf>,
+}
+
+impl Settings {
+    // The settings used when no options are given
+    pub fn new() -> Settings {
+        Settings {
+            sparse: true,
+            direct: false,
+            force: false,
+            preallocate: false,
+            async_writes: false,
+            hash: false,
+            verify: false,
+            verify_reset: false,
+            confirm_reads: 1,
+            vote_reads: 0,
+            status_format: StatusFormat::Plain,
+            progress_interval: PLAIN_REFRESH_INTERVAL,
+            quiet: false,
+            log_rates: None,
+            log_reads: None,
+            log_events: false,
+            latency_map: false,
+            metrics_port: None,
+            control_socket: None,
+        }
+    }
+}
+
+// Data returned by each read so far of a range which is being reread for confirmation
+#[derive(Debug)]
+struct Confirmation {
+    variants: Vec<Vec<u8>>,
+}
+
+#[derive(Debug)]
+enum ConfirmOutcome {
+    // The range is being reread, or has been marked as bad
+    Pending,
+    // Enough reads agreed
+    Confirmed,
+    // Reads disagreed, and this data was reconstructed from them by majority vote
+    Reconstructed(Vec<u8>),
+}
+
+// Reasons a rescued region failed verification
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum VerifyFailure {
+    Mismatch,
+    Unreadable,
+}
+
+#[derive(Debug)]
+pub struct Recover<I: Input, S: Sink> {
+    block: I,
+    map_file: MapFile,
+    map_file_path: PathBuf,
+    output: S,
+    start: Instant,
+    last_sync: Instant,
+    last_success: Option<Instant>,
+    last_print: Option<Instant>,
+    histogram: HashMap<SectorState, u64>,
+    bad_areas: usize,
+    buffer_cache: Vec<Buffer>,
+    stats: Stats,
+    settings: Settings,
+    image_hash: Option<ImageHash>,
+    image_hash_path: PathBuf,
+    // Ranges awaiting further reads before they can be marked as rescued, keyed by offset
+    confirmations: HashMap<u64, Confirmation>,
+    confirm_queue: VecDeque<Range<u64>>,
+    unstable_log: UnstableLog,
+    // Regions rescued by majority vote are marked as rescued, and all others as non-tried
+    reconstructed: Option<MapFile>,
+    reconstructed_path: PathBuf,
+    tui: Option<Tui>,
+    rates_log: Option<RatesLog>,
+    reads_log: Option<ReadsLog>,
+    event_log: Option<EventLog>,
+    latency_map: Option<LatencyMap>,
+    latency_map_path: PathBuf,
+    metrics: Option<MetricsServer>,
+    last_metrics: Option<Instant>,
+    read_latency: LatencyHistogram,
+    control: Option<ControlSocket>,
+    paused: bool,
+    // Maximum number of reads in flight, which may be lowered through the control socket
+    queue_depth: usize,
+    skip_area: bool,
+    skip_phase: bool,
+}
+
+impl<I: Input, S: Sink> Recover<I, S> {
+    pub fn new(block: I, mut output: S, mapfile_path: &str, settings: Settings) -> Result<Recover<I, S>, RecoverError> {
+        let map_path = Path::new(mapfile_path);
+        let existing = MapFile::read_first_valid(map_path, block.get_size_bytes())
+            .map_err(|e| RecoverError::map(map_path.to_path_buf(), e))?;
+        let map = match existing {
+            Some((map, warning)) => {
+                if let Some(warning) = warning {
+                    eprintln!("Warning: {}", warning);
+                }
+                map
+            },
+            None => {
+                let map = match output.read_embedded_map().map_err(RecoverError::output)? {
+                    Some(data) => MapFile::read_validated(&data[..], block.get_size_bytes()).map_err(RecoverError::output)?,
+                    None => MapFile::new(block.get_size_bytes()),
+                };
+                map.write_to_path(map_path)?;
+                map
+            },
+        };
+
+        // Hashing continues automatically if an earlier run started it
+        let image_hash_path = ImageHash::get_path(map_path);
+        let existing_hash = ImageHash::read_from_path(&image_hash_path, block.get_size_bytes())
+            .map_err(|e| RecoverError::map(image_hash_path.clone(), e))?;
+        let image_hash = match existing_hash {
+            Some(mut hash) => {
+                hash.reconcile(&map);
+                Some(hash)
+            },
+            None if settings.hash => Some(ImageHash::new(block.get_size_bytes(), image_hash::DEFAULT_CHUNK_SIZE)),
+            None => None,
+        };
+
+        // As with digests, tracking continues automatically if an earlier run started it
+        let latency_map_path = LatencyMap::get_path(map_path);
+        let existing_latency_map = LatencyMap::read_from_path(&latency_map_path, block.get_size_bytes())
+            .map_err(|e| RecoverError::map(latency_map_path.clone(), e))?;
+        let latency_map = match existing_latency_map {
+            Some(latency_map) => Some(latency_map),
+            None if settings.latency_map => Some(LatencyMap::new(block.get_size_bytes())),
+            None => None,
+        };
+
+        let metrics = match settings.metrics_port {
+            Some(port) => Some(MetricsServer::start(port)?),
+            None => None,
+        };
+
+        let control = match settings.control_socket {
+            Some(ref path) => Some(ControlSocket::bind(path)?),
+            None => None,
+        };
+
+        let reconstructed_path = atomic_file::generation_path(map_path, RECONSTRUCTED_SUFFIX);
+        let reconstructed = if reconstructed_path.exists() {
+            let file = File::open(&reconstructed_path).map_err(|e| RecoverError::map(reconstructed_path.clone(), e))?;
+            let map = MapFile::read_validated(file, block.get_size_bytes())
+                .map_err(|e| RecoverError::map(reconstructed_path.clone(), e))?;
+            Some(map)
+        } else if settings.vote_reads > 0 {
+            Some(MapFile::new(block.get_size_bytes()))
+        } else {
+            None
+        };
+
+        // Verification has no status display to draw
+        let tui = if settings.status_format == StatusFormat::Tui && !settings.verify {
+            Some(Tui::new()?)
+        } else {
+            None
+        };
+
+        let rates_log = match settings.log_rates {
+            Some(ref path) => Some(RatesLog::open(path)?),
+            None => None,
+        };
+        let reads_log = match settings.log_reads {
+            Some(ref path) => Some(ReadsLog::open(path)?),
+            None => None,
+        };
+        let event_log = if settings.log_events {
+            Some(EventLog::open(&atomic_file::generation_path(map_path, logs::EVENTS_SUFFIX))?)
+        } else {
+            None
+        };
+
+        let queue_depth = block.max_requests();
+        let histogram = map.get_histogram();
+        let bad_areas = map.iter().filter(|r| r.tag == SectorState::Bad).count();
+        let result = Recover {
+            block: block,
+            map_file: map,
+            map_file_path: map_path.to_path_buf(),
+            output: output,
+            start: Instant::now(),
+            last_sync: Instant::now(),
+            last_success: None,
+            last_print: None,
+            histogram: histogram,
+            bad_areas: bad_areas,
+            buffer_cache: Vec::new(),
+            stats: Stats::new(),
+            settings: settings,
+            image_hash: image_hash,
+            image_hash_path: image_hash_path,
+            confirmations: HashMap::new(),
+            confirm_queue: VecDeque::new(),
+            unstable_log: UnstableLog::for_map(map_path),
+            reconstructed: reconstructed,
+            reconstructed_path: reconstructed_path,
+            tui: tui,
+            rates_log: rates_log,
+            reads_log: reads_log,
+            event_log: event_log,
+            latency_map: latency_map,
+            latency_map_path: latency_map_path,
+            metrics: metrics,
+            last_metrics: None,
+            read_latency: LatencyHistogram::new(),
+            control: control,
+            paused: false,
+            queue_depth: queue_depth,
+            skip_area: false,
+            skip_phase: false,
+        };
+        Ok(result)
+    }
+
+    fn should_run(&self) -> bool {
+        !signals::is_interrupted()
+    }
+
+    // The map is only written once the output has been synced, so the map on disk never records
+    // data as rescued before it is durable
+    fn do_sync(&mut self) -> Result<(), Box<Error>> {
+        self.output.sync().map_err(RecoverError::output)?;
+        self.write_map()?;
+        let flushed = match self.reads_log {
+            Some(ref mut log) => log.flush(),
+            None => Ok(()),
+        };
+        if let Err(err) = flushed {
+            self.reads_log = None;
+            self.report_log_error("reads", err);
+        }
+        self.log_event("sync", "");
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    fn write_map(&mut self) -> Result<(), Box<Error>> {
+        self.map_file.write_to_path(&self.map_file_path)?;
+        let mut data = Vec::new();
+        self.map_file.write_to_stream(&mut data)?;
+        self.output.embed_map(&data).map_err(RecoverError::output)?;
+        if let Some(ref reconstructed) = self.reconstructed {
+            reconstructed.write_to_path(&self.reconstructed_path)?;
+        }
+        if let Some(ref latency_map) = self.latency_map {
+            latency_map.write_to_path(&self.latency_map_path)?;
+        }
+        if let Some(ref hash) = self.image_hash {
+            hash.write_to_path(&self.image_hash_path)?;
+        }
+        Ok(())
+    }
+
+    fn update_status(&mut self) {
+        self.update_rates_log();
+        self.update_metrics();
+        if self.settings.quiet {
+            return;
+        }
+        let now = Instant::now();
+        match self.last_print {
+            None => {
+                self.print_status(false);
+                self.last_print = Some(now);
+            },
+            Some(previous) => {
+                let duration = now.duration_since(previous);
+                let seconds = duration.as_secs() as f32 + duration.subsec_nanos() as f32 * 1e-9;
+                if seconds > self.settings.progress_interval {
+                    self.print_status(true);
+                    self.last_print = Some(now);
+                }
+            },
+        }
+    }
+
+    // Carries out any requests received through signals or the control socket
+    fn handle_requests(&mut self) -> Result<(), Box<Error>> {
+        if signals::take_sync_request() {
+            self.do_sync()?;
+        }
+        if signals::take_dump_request() {
+            self.print_status_dump();
+        }
+        loop {
+            let request = match self.control.as_ref().and_then(|c| c.try_next()) {
+                Some(request) => request,
+                None => return Ok(()),
+            };
+            let reply = match request.command {
+                Command::Pause => {
+                    self.paused = true;
+                    self.log_event("pause", "");
+                    String::from("ok")
+                },
+                Command::Resume => {
+                    self.paused = false;
+                    self.log_event("resume", "");
+                    String::from("ok")
+                },
+                Command::Sync => {
+                    self.do_sync()?;
+                    String::from("ok")
+                },
+                Command::QueueDepth(depth) => {
+                    self.queue_depth = cmp::min(depth, self.block.max_requests());
+                    format!("ok: queue depth {}", self.queue_depth)
+                },
+                Command::Skip => {
+                    self.skip_area = true;
+                    String::from("ok")
+                },
+                Command::NextPhase => {
+                    self.skip_phase = true;
+                    String::from("ok")
+                },
+                Command::Status => {
+                    let extra = [
+                        ("paused", JsonValue::Bool(self.paused)),
+                        ("queue_depth", JsonValue::Number(self.queue_depth as u64)),
+                        ("pending", JsonValue::Number(self.block.requests_pending() as u64)),
+                    ];
+                    self.get_status().to_json("status", &extra)
+                },
+            };
+            request.reply(&reply);
+        }
+    }
+
+    // Completes the reads in flight, saves the progress made and waits until the rescue is resumed
+    // or interrupted
+    fn wait_while_paused(&mut self, phase_target: &SectorState) -> Result<(), Box<Error>> {
+        while self.block.requests_pending() > 0 {
+            self.try_drain_request(phase_target)?;
+            self.update_status();
+        }
+        self.do_sync()?;
+        while self.paused && self.should_run() {
+            thread::sleep(Duration::from_millis(PAUSE_POLL_INTERVAL_MS));
+            self.handle_requests()?;
+            self.update_status();
+        }
+        Ok(())
+    }
+
+    // Abandons the rest of the area being read, from the furthest read in flight. The position has
+    // already moved past that read, possibly to the start of the next area, so it cannot be used.
+    fn skip_current_area(&mut self, phase_target: &SectorState) {
+        self.skip_area = false;
+        let pos = self.map_file.get_pos();
+        let current = self.block.last_pending_read().unwrap_or(pos);
+        let area = self.map_file.iter_range(current..self.map_file.get_size())
+            .find(|r| r.tag == *phase_target && r.length > 0)
+            .map(|r| r.as_range());
+        if let Some(area) = area {
+            self.map_file.set_pos(cmp::max(pos, area.end));
+            self.log_event("skip", &format!("0x{:08X}  0x{:08X}", area.start, area.end - area.start));
+        }
+    }
+
+    // Waits for any requests in flight to complete without recording their results, so that the
+    // regions they cover keep their previous state in the map. Linux cannot cancel reads of block
+    // devices, so there is no quicker way to stop.
+    fn abandon_requests(&mut self) -> Result<(), nix::Error> {
+        self.confirm_queue.clear();
+        self.confirmations.clear();
+        while self.block.requests_pending() > 0 {
+            match self.block.get_completed_request() {
+                Ok(request) => self.recycle_buffer(request.reclaim_buffer()),
+                Err(nix::Error::Sys(nix::Errno::EINTR)) => {},
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    // Runs the phases, making sure that the progress made is saved however they end. Panics are
+    // treated as errors so that they do not escape before the map has been written.
+    pub fn run_phases(&mut self) -> Result<(), Box<Error>> {
+        let result: Result<(), Box<Error>> = match panic::catch_unwind(AssertUnwindSafe(|| self.do_phases())) {
+            Ok(result) => result,
+            Err(payload) => {
+                let message = match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+                    (Some(message), _) => message.to_string(),
+                    (_, Some(message)) => message.clone(),
+                    _ => String::from("unknown panic"),
+                };
+                Err(Box::new(io::Error::new(io::ErrorKind::Other, format!("Internal error: {}", message))))
+            },
+        };
+        if let Err(ref err) = result {
+            // do_phases has already saved the map unless it failed
+            self.log_event("error", &err.to_string());
+            // Draining fails when AIO itself is broken, which is likely to be the error just
+            // reported. Reads which were never recorded leave the map unaffected, so it is saved
+            // all the same.
+            let _ = self.abandon_requests();
+            if let Err(sync_err) = self.do_sync() {
+                self.print_error_line(format!("Unable to save progress to the map file: {}", sync_err));
+            }
+        }
+        result
+    }
+
+    fn can_submit(&self) -> bool {
+        self.block.requests_avail() > 0 && self.block.requests_pending() < self.queue_depth
+    }
+
+    fn log_event(&mut self, event: &str, details: &str) {
+        let result = match self.event_log {
+            Some(ref mut log) => log.record(event, details),
+            None => return,
+        };
+        if let Err(err) = result {
+            self.event_log = None;
+            self.report_log_error("event", err);
+        }
+    }
+
+    // A log which cannot be written is not worth stopping the rescue of a failing drive for, so
+    // it is given up after reporting the error
+    fn report_log_error(&mut self, log: &str, err: io::Error) {
+        self.print_error_line(format!("Unable to write the {} log, which has been disabled: {}", log, err));
+    }
+
+    fn update_metrics(&mut self) {
+        let due = match self.last_metrics {
+            Some(previous) => previous.elapsed().as_secs() >= METRICS_INTERVAL,
+            None => true,
+        };
+        if let (true, Some(ref metrics)) = (due, self.metrics.as_ref()) {
+            let extra = metrics::Extra {
+                queue_depth: self.block.requests_pending(),
+                queue_capacity: self.block.max_requests(),
+                read_latency: &self.read_latency,
+                latency_map: self.latency_map.as_ref().map(|m| m.get_histogram()),
+            };
+            metrics.update(metrics::render(&self.get_status(), &extra));
+            self.last_metrics = Some(Instant::now());
+        }
+    }
+
+    fn update_rates_log(&mut self) {
+        let bad_size = self.get_histogram_value(SectorState::Bad);
+        let elapsed = Instant::now().duration_since(self.start).as_secs();
+        let result = match self.rates_log {
+            Some(ref mut log) if log.is_due() => {
+                log.write(self.map_file.get_pos(), self.stats.good, elapsed, self.bad_areas, bad_size)
+            },
+            _ => return,
+        };
+        if let Err(err) = result {
+            self.rates_log = None;
+            self.report_log_error("rates", err);
+        }
+    }
+
+    fn get_status(&self) -> Status {
+        let now = Instant::now();
+        Status {
+            phase: self.map_file.get_phase(),
+            pass: self.map_file.get_pass(),
+            pos: self.map_file.get_pos(),
+            size: self.map_file.get_size(),
+            rescued: self.get_histogram_value(SectorState::Rescued),
+            bad: self.get_histogram_value(SectorState::Bad),
+            untried: self.get_histogram_value(SectorState::Untried),
+            untrimmed: self.get_histogram_value(SectorState::Untrimmed),
+            unscraped: self.get_histogram_value(SectorState::Unscraped),
+            read_bytes: self.stats.good,
+            error_bytes: self.stats.bad,
+            unstable: self.stats.unstable,
+            reconstructed: self.stats.reconstructed,
+            slow: self.latency_map.as_ref().map(|m| m.get_slow_bytes()),
+            allocated: self.output.get_allocated_bytes().ok(),
+            apparent_size: self.output.get_size_bytes().ok(),
+            sparse: self.output.is_sparse(),
+            elapsed: now.duration_since(self.start).as_secs(),
+            since_success: self.last_success.map(|time| now.duration_since(time).as_secs()),
+        }
+    }
+
+    fn print_status(&mut self, overwrite: bool) {
+        let status = self.get_status();
+        match self.settings.status_format {
+            StatusFormat::Text => self.print_status_text(&status, overwrite),
+            StatusFormat::Plain => println!("{}", self.format_status_line(&status)),
+            StatusFormat::Json => println!("{}", status.to_json("status", &[])),
+            StatusFormat::Tui => {
+                if let Some(ref mut tui) = self.tui {
+                    // A failed redraw is not worth stopping the rescue for
+                    let _ = tui.draw(&status, &self.map_file);
+                }
+            },
+        }
+    }
+
+    fn print_phase_change(&self) {
+        let status = self.get_status();
+        match self.settings.status_format {
+            // The display already shows the phase
+            StatusFormat::Text | StatusFormat::Tui => {},
+            StatusFormat::Plain => println!("Starting phase {}", status.phase.name()),
+            StatusFormat::Json => println!("{}", status.to_json("phase", &[])),
+        }
+    }
+
+    fn format_status_line(&self, status: &Status) -> String {
+        let last_success = match status.since_success {
+            None => String::from("never"),
+            Some(seconds) => format!("{} ago", status::format_seconds(seconds)),
+        };
+        format!("[{}] {} (pass {}): ipos {}, rescued {}, bad {}, non-tried {}, non-trimmed {}, non-scraped {}, \
+                 read rate {}, error rate {}, last success {}, remaining {}",
+                status::format_seconds(status.elapsed), status.phase.name(), status.pass,
+                self.format_bytes_with_percentage(status.pos),
+                self.format_bytes_with_percentage(status.rescued),
+                self.format_bytes_with_percentage(status.bad),
+                self.format_bytes_with_percentage(status.untried),
+                self.format_bytes_with_percentage(status.untrimmed),
+                self.format_bytes_with_percentage(status.unscraped),
+                status::format_rate(status.read_bytes, status.elapsed),
+                status::format_rate(status.error_bytes, status.elapsed),
+                last_success,
+                status::format_seconds(status.remaining_seconds()))
+    }
+
+    // Printed to standard error on request, so as not to disturb the status display
+    fn print_status_dump(&mut self) {
+        let status = self.get_status();
+        let optional_bytes = |bytes: Option<u64>| bytes.map_or(String::from("unknown"), |b| status::format_bytes(b));
+        let lines = vec![
+            format!("Status at {}:", logs::format_timestamp(SystemTime::now())),
+            format!("  phase {} (pass {}), ipos {}{}", status.phase.name(), status.pass,
+                    self.format_bytes_with_percentage(status.pos), if self.paused { ", paused" } else { "" }),
+            format!("  rescued {}, bad {}, non-tried {}, non-trimmed {}, non-scraped {}",
+                    self.format_bytes_with_percentage(status.rescued),
+                    self.format_bytes_with_percentage(status.bad),
+                    self.format_bytes_with_percentage(status.untried),
+                    self.format_bytes_with_percentage(status.untrimmed),
+                    self.format_bytes_with_percentage(status.unscraped)),
+            format!("  this run: read {}, errors {}, unstable {}, reconstructed {}, slow {}",
+                    status::format_bytes(status.read_bytes), status::format_bytes(status.error_bytes),
+                    status::format_bytes(status.unstable), status::format_bytes(status.reconstructed),
+                    optional_bytes(status.slow)),
+            format!("  read rate {}, error rate {}, total rate {}",
+                    status::format_rate(status.read_bytes, status.elapsed),
+                    status::format_rate(status.error_bytes, status.elapsed),
+                    status::format_rate(status.total_bytes(), status.elapsed)),
+            format!("  requests: {} completed, {} in flight, queue depth {} of {}, {} awaiting confirmation",
+                    self.stats.requests, self.block.requests_pending(), self.queue_depth, self.block.max_requests(),
+                    self.confirmations.len()),
+            format!("  output: allocated {}, apparent size {}, sparse {}",
+                    optional_bytes(status.allocated), optional_bytes(status.apparent_size),
+                    if status.sparse { "yes" } else { "no" }),
+            format!("  run time {}, last success {}, last sync {} ago, remaining {}",
+                    status::format_seconds(status.elapsed),
+                    status.since_success.map_or(String::from("never"), |s| format!("{} ago", status::format_seconds(s))),
+                    status::format_seconds(self.last_sync.elapsed().as_secs()),
+                    status::format_seconds(status.remaining_seconds())),
+        ];
+        for line in lines {
+            self.print_error_line(line);
+        }
+    }
+
+    // Standard error is only visible once the full-screen display has been left
+    fn print_error_line(&mut self, line: String) {
+        match self.tui {
+            Some(ref mut tui) => tui.hold_message(line),
+            None => eprintln!("{}", line),
+        }
+    }
+
+    // Printed once the rescue stops, whether or not it has finished
+    pub fn print_summary(&mut self) {
+        // Leave the full-screen display so that the summary remains visible
+        self.tui = None;
+        let digest = self.image_hash.as_ref()
+            .and_then(|h| h.get_image_digest())
+            .map(|d| sha256::to_hex(&d));
+        if self.settings.status_format != StatusFormat::Json {
+            let status = self.get_status();
+            if self.settings.status_format == StatusFormat::Text && !self.settings.quiet {
+                println!();
+            }
+            println!("{}: rescued {}, bad {}, remaining {}, run time {}",
+                     if status.phase == Phase::Finished { "Finished" } else { "Stopped" },
+                     self.format_bytes_with_percentage(status.rescued),
+                     self.format_bytes_with_percentage(status.bad),
+                     self.format_bytes_with_percentage(status.remaining_bytes()),
+                     status::format_seconds(status.elapsed));
+            if let Some(ref digest) = digest {
+                println!("Image SHA-256: {}", digest);
+            }
+            return;
+        }
+        let extra = [
+            ("finished", JsonValue::Bool(self.map_file.get_phase() == Phase::Finished)),
+            ("interrupted", JsonValue::Bool(!self.should_run())),
+            ("image_sha256", digest.as_ref().map_or(JsonValue::Null, |d| JsonValue::Str(d))),
+        ];
+        println!("{}", self.get_status().to_json("summary", &extra));
+    }
+
+    fn print_status_text(&self, status: &Status, overwrite: bool) {
+        let key_width = 13;
+        let value_width = 19;
+        if overwrite {
+            print!("{}{}", ansi_escapes::CursorLeft, ansi_escapes::CursorUp(8));
+        }
+        println!("Press Ctrl+C to exit.{}\n{}", ansi_escapes::EraseEndLine, ansi_escapes::EraseEndLine);
+        let phase = format!("{} (pass {})", status.phase.name(), status.pass);
+        if self.settings.vote_reads > 0 {
+            println!("{:>kw$}: {:vw$} {:>kw$}: {:vw$} {:>kw$}: {:vw$}{}",
+                     "Phase", phase,
+                     "unstable", status::format_bytes(status.unstable),
+                     "reconstructed", status::format_bytes(status.reconstructed),
+                     ansi_escapes::EraseEndLine,
+                     kw = key_width,
+                     vw = value_width);
+        } else if self.settings.confirm_reads > 1 {
+            println!("{:>kw$}: {:vw$} {:>kw$}: {:vw$}{}",
+                     "Phase", phase,
+                     "unstable", status::format_bytes(status.unstable),
+                     ansi_escapes::EraseEndLine,
+                     kw = key_width,
+                     vw = value_width);
+        } else {
+            println!("{:>kw$}: {:vw$}{}", "Phase", phase,
+                     ansi_escapes::EraseEndLine,
+                     kw = key_width,
+                     vw = value_width);
+        }
+        println!("{:>kw$}: {:vw$} {:>kw$}: {:vw$} {:>kw$}: {:vw$}{}",
+                 "ipos", self.format_bytes_with_percentage(status.pos),
+                 "rescued", self.format_bytes_with_percentage(status.rescued),
+                 "bad", self.format_bytes_with_percentage(status.bad),
+                 ansi_escapes::EraseEndLine,
+                 kw = key_width,
+                 vw = value_width);
+
+        println!("{:>kw$}: {:vw$} {:>kw$}: {:vw$} {:>kw$}: {:vw$}{}",
+                 "non-tried", self.format_bytes_with_percentage(status.untried),
+                 "non-trimmed", self.format_bytes_with_percentage(status.untrimmed),
+                 "non-scraped", self.format_bytes_with_percentage(status.unscraped),
+                 ansi_escapes::EraseEndLine,
+                 kw = key_width,
+                 vw = value_width);
+
+        let allocated = status.allocated.map(|b| status::format_bytes(b));
+        let apparent = status.apparent_size.map(|b| status::format_bytes(b));
+        println!("{:>kw$}: {:vw$} {:>kw$}: {:vw$} {:>kw$}: {:vw$}{}",
+                 "allocated", allocated.unwrap_or(String::from("unknown")),
+                 "apparent size", apparent.unwrap_or(String::from("unknown")),
+                 "sparse", if status.sparse { "yes" } else { "no" },
+                 ansi_escapes::EraseEndLine,
+                 kw = key_width,
+                 vw = value_width);
+
+        println!("{:>kw$}: {:vw$} {:>kw$}: {:vw$} {:>kw$}: {:vw$}{}",
+                 "read rate", status::format_rate(status.read_bytes, status.elapsed),
+                 "error rate", status::format_rate(status.error_bytes, status.elapsed),
+                 "total rate", status::format_rate(status.total_bytes(), status.elapsed),
+                 ansi_escapes::EraseEndLine,
+                 kw = key_width,
+                 vw = value_width);
+
+        let last_success = match status.since_success {
+            None => String::from("never"),
+            Some(seconds) => format!("{} ago", status::format_seconds(seconds)),
+        };
+        println!("{:>kw$}: {:vw$} {:>kw$}: {:vw$} {:>kw$}: {:vw$}{}",
+                 "run time", status::format_seconds(status.elapsed),
+                 "last success", last_success,
+                 "remaining", status::format_seconds(status.remaining_seconds()),
+                 ansi_escapes::EraseEndLine,
+                 kw = key_width,
+                 vw = value_width);
+        print!("{}{}", ansi_escapes::EraseEndLine, ansi_escapes::CursorLeft);
+    }
+
+    fn format_bytes_with_percentage(&self, bytes: u64) -> String {
+        let percentage = (bytes as f64) * 100.0 / (self.map_file.get_size() as f64);
+        format!("{} ({:.1}%)", status::format_bytes(bytes), percentage)
+    }
+
+    fn get_histogram_value(&self, state: SectorState) -> u64 {
+        *self.histogram.get(&state).unwrap_or(&0)
+    }
+
+    fn update_histogram(&mut self, bytes: u64, from: SectorState, to: SectorState) {
+        *self.histogram.entry(from).or_insert(0) -= bytes;
+        *self.histogram.entry(to).or_insert(0) += bytes;
+    }
+
+    fn do_phase(&mut self) -> Result<(), Box<Error>> {
+        self.map_file.set_pass(1);
+        match self.map_file.get_phase().target_sectors() {
+            Some(phase_target) => {
+                while self.get_histogram_value(phase_target) > 0 && self.should_run() && !self.skip_phase {
+                    self.do_pass(&phase_target)?;
+                    if self.is_pass_complete() && !self.skip_phase {
+                        self.map_file.set_pos(0);
+                        self.map_file.next_pass();
+                        let details = format!("{} pass {}", self.map_file.get_phase().name(), self.map_file.get_pass());
+                        self.log_event("pass", &details);
+                    }
+                }
+            },
+            None => {},
+        }
+        Ok(())
+    }
+
+    fn do_phases(&mut self) -> Result<(), Box<Error>> {
+        let details = format!("{} pass {} at 0x{:08X}", self.map_file.get_phase().name(),
+                              self.map_file.get_pass(), self.map_file.get_pos());
+        self.log_event("start", &details);
+        self.update_status();
+        // Chunks which became final in a run which was stopped before they were hashed
+        self.catch_up_image_hash()?;
+        let mut finished = false;
+        while !finished && self.should_run() {
+            self.handle_requests()?;
+            if self.is_phase_complete() || self.skip_phase {
+                self.skip_phase = false;
+                let current_phase = self.map_file.get_phase();
+                match current_phase.next() {
+                    Some(phase) => {
+                        self.map_file.set_phase(&phase);
+                        self.map_file.set_pos(0);
+                        self.print_phase_change();
+                        self.log_event("phase", &phase.name());
+                    },
+                    None => finished = true,
+                }
+            } else {
+                self.do_phase()?;
+            }
+        }
+        if finished {
+            // Chunks containing bad sectors can only be hashed now that they will not be retried
+            self.catch_up_image_hash()?;
+        } else {
+            let details = signals::get_interrupting_signal().map_or(String::new(), |s| format!("{:?}", s));
+            self.log_event("interrupted", &details);
+        }
+        self.do_sync()?;
+        Ok(())
+    }
+
+    // Hashes every chunk which is final but has no digest yet, a chunk at a time so that progress
+    // is shown and the rescue can be stopped part way through
+    fn catch_up_image_hash(&mut self) -> Result<(), Box<Error>> {
+        let chunk_size = match self.image_hash {
+            Some(ref hash) => hash.get_chunk_size(),
+            None => return Ok(()),
+        };
+        let mut pos = 0;
+        while pos < self.map_file.get_size() && self.should_run() {
+            let end = cmp::min(pos + chunk_size, self.map_file.get_size());
+            if let Some(ref mut hash) = self.image_hash {
+                hash.update(&self.map_file, pos..end, &mut self.output).map_err(RecoverError::output)?;
+            }
+            pos = end;
+            self.handle_requests()?;
+            self.update_status();
+        }
+        Ok(())
+    }
+
+    fn is_pass_complete(&self) -> bool {
+        let current_phase = self.map_file.get_phase();
+        match current_phase.target_sectors() {
+            Some(phase_target) => {
+                (&self.map_file).iter_range(self.map_file.get_pos()..self.map_file.get_size())
+                .filter(|r| r.tag == phase_target).next().is_none()
+            },
+            None => true,
+        }
+    }
+
+    fn is_phase_complete(&self) -> bool {
+        let current_phase = self.map_file.get_phase();
+        match current_phase.target_sectors() {
+            Some(phase_target) => {
+                (&self.map_file).iter_range(0..self.map_file.get_size())
+                .filter(|r| r.tag == phase_target).next().is_none()
+            },
+            None => true,
+        }
+    }
+
+    fn get_cleared_buffer(&mut self) -> Buffer {
+        let sectors_per_buffer = self.block.get_block_size_physical() / self.block.get_sector_size();
+        let mut buffer = match self.buffer_cache.pop() {
+            Some(buffer) => buffer,
+            None => self.block.create_io_buffer(sectors_per_buffer),
+        };
+        buffer.clear();
+        buffer
+    }
+
+    fn recycle_buffer(&mut self, buffer: Buffer) {
+        self.buffer_cache.push(buffer)
+    }
+
+    fn try_drain_request(&mut self, phase_target: &SectorState) -> Result<(), Box<Error>> {
+        if self.block.requests_pending() > 0 {
+            let request = match self.block.get_completed_request() {
+                Ok(r) => r,
+                Err(nix::Error::Sys(nix::Errno::EINTR)) => return Ok(()),
+                Err(err) => return Err(Box::new(RecoverError::Aio(err))),
+            };
+            if request.operation == Operation::Read {
+                self.log_read(&request);
+                if let Some(latency) = request.get_latency() {
+                    self.read_latency.record(latency);
+                    if let Some(ref mut latency_map) = self.latency_map {
+                        latency_map.record(request.offset..(request.offset + request.size), latency);
+                    }
+                }
+            }
+            match request.operation {
+                Operation::Read => self.complete_read(request, phase_target)?,
+                Operation::Write => self.complete_write(request, phase_target)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn log_read(&mut self, request: &Request) {
+        let result = match self.reads_log {
+            Some(ref mut log) => log.record(request.offset, request.size, request.result, request.get_latency()),
+            None => return,
+        };
+        if let Err(err) = result {
+            self.reads_log = None;
+            self.report_log_error("reads", err);
+        }
+    }
+
+    fn complete_read(&mut self, request: Request, phase_target: &SectorState) -> Result<(), Box<Error>> {
+        self.stats.requests += 1;
+        if request.result > 0 && self.settings.confirm_reads > 1 && *phase_target != SectorState::Untried {
+            match self.confirm_read(&request, phase_target)? {
+                ConfirmOutcome::Confirmed => {},
+                ConfirmOutcome::Pending => {
+                    self.recycle_buffer(request.reclaim_buffer());
+                    return Ok(());
+                },
+                ConfirmOutcome::Reconstructed(data) => {
+                    let offset = request.offset;
+                    self.recycle_buffer(request.reclaim_buffer());
+                    return self.complete_reconstructed(offset, data, phase_target);
+                },
+            }
+        }
+        if request.result > 0 {
+            let request_result = request.result as u64;
+            let rescued = request.offset..(request.offset + request_result);
+            self.last_success = Some(Instant::now());
+            self.stats.good += request_result;
+            if request.is_data_zeros() {
+                self.output.write_zeros(rescued.clone()).map_err(RecoverError::output)?;
+            } else if self.settings.async_writes {
+                // Only possible when writing directly to a file
+                // The region is marked as rescued once the write completes
+                if let Some(fd) = self.output.raw_fd() {
+                    self.block.submit_write(fd, request).map_err(RecoverError::output)?;
+                    return Ok(());
+                }
+                self.output.write_at(request.offset, request.get_data()).map_err(RecoverError::output)?;
+            } else {
+                self.output.write_at(request.offset, request.get_data()).map_err(RecoverError::output)?;
+            }
+            self.mark_rescued(rescued, phase_target)?;
+        } else {
+            self.confirmations.remove(&request.offset);
+            self.mark_bad(request.offset..(request.offset + request.size), phase_target);
+        };
+        self.recycle_buffer(request.reclaim_buffer());
+        Ok(())
+    }
+
+    // Decides whether a range can be considered rescued once enough reads of it have returned the
+    // same data. If reads disagree, the range is either marked as bad, or reread until there are
+    // enough reads to reconstruct it by majority vote.
+    fn confirm_read(&mut self, request: &Request, phase_target: &SectorState) -> io::Result<ConfirmOutcome> {
+        let range = request.offset..(request.offset + request.size);
+        let mut confirmation = match self.confirmations.remove(&request.offset) {
+            Some(confirmation) => confirmation,
+            None => Confirmation {
+                variants: Vec::new(),
+            },
+        };
+        let was_stable = confirmation.variants.iter().all(|v| v[..] == *confirmation.variants[0]);
+        confirmation.variants.push(request.get_data().to_vec());
+        if *confirmation.variants[0] != *request.get_data() {
+            let sector_size = self.block.get_sector_size();
+            let differing = self.unstable_log.record(request.offset, sector_size, &confirmation.variants[0], request.get_data())?;
+            if was_stable {
+                self.stats.unstable += differing;
+            }
+            if self.settings.vote_reads == 0 {
+                self.mark_bad(range, phase_target);
+                return Ok(ConfirmOutcome::Pending);
+            }
+        }
+        let stable = confirmation.variants.iter().all(|v| v[..] == *confirmation.variants[0]);
+        if stable && confirmation.variants.len() >= self.settings.confirm_reads {
+            return Ok(ConfirmOutcome::Confirmed);
+        } else if !stable && confirmation.variants.len() >= self.settings.vote_reads {
+            return Ok(ConfirmOutcome::Reconstructed(vote::majority_vote(&confirmation.variants)));
+        }
+        self.confirmations.insert(request.offset, confirmation);
+        self.confirm_queue.push_back(range);
+        Ok(ConfirmOutcome::Pending)
+    }
+
+    fn complete_reconstructed(&mut self, offset: u64, data: Vec<u8>, phase_target: &SectorState) -> Result<(), Box<Error>> {
+        let rescued = offset..(offset + data.len() as u64);
+        self.last_success = Some(Instant::now());
+        self.stats.good += data.len() as u64;
+        self.stats.reconstructed += data.len() as u64;
+        if data.iter().all(|v| *v == 0) {
+            self.output.write_zeros(rescued.clone()).map_err(RecoverError::output)?;
+        } else {
+            // An aligned buffer is required to write to an O_DIRECT output
+            let mut buffer = self.get_cleared_buffer();
+            buffer.as_mut_slice()[..data.len()].copy_from_slice(&data);
+            let result = self.output.write_at(offset, &buffer.as_slice()[..data.len()]);
+            self.recycle_buffer(buffer);
+            result.map_err(RecoverError::output)?;
+        }
+        self.mark_rescued(rescued.clone(), phase_target)?;
+        if let Some(ref mut reconstructed) = self.reconstructed {
+            reconstructed.put(rescued, SectorState::Rescued);
+        }
+        Ok(())
+    }
+
+    fn complete_write(&mut self, request: Request, phase_target: &SectorState) -> Result<(), Box<Error>> {
+        let written = request.offset..(request.offset + request.size);
+        let result = request.result;
+        self.recycle_buffer(request.reclaim_buffer());
+        if result < 0 {
+            return Err(Box::new(RecoverError::output(io::Error::from_raw_os_error(-result as i32))));
+        } else if result as u64 != written.end - written.start {
+            return Err(Box::new(RecoverError::output(io::Error::new(io::ErrorKind::WriteZero, "Short write to output"))));
+        }
+        self.mark_rescued(written, phase_target)?;
+        Ok(())
+    }
+
+    // The number of bad areas overlapping or adjoining `range`. Only these can be affected by a
+    // change to the state of `range`, so the total can be kept up to date without scanning the map.
+    fn count_bad_areas_around(&self, range: &Range<u64>) -> usize {
+        let around = range.start.saturating_sub(1)..cmp::min(range.end + 1, self.map_file.get_size());
+        self.map_file.iter_range(around).filter(|r| r.tag == SectorState::Bad && r.length > 0).count()
+    }
+
+    fn mark_bad(&mut self, range: Range<u64>, phase_target: &SectorState) {
+        let bad_areas_before = self.count_bad_areas_around(&range);
+        self.update_histogram(range.end - range.start, *phase_target, SectorState::Bad);
+        self.map_file.put(range.clone(), SectorState::Bad);
+        self.bad_areas = self.bad_areas - bad_areas_before + self.count_bad_areas_around(&range);
+        self.stats.bad += range.end - range.start;
+        // Failures which extend a known bad area, e.g. each sector in turn while scraping, or which
+        // repeat one while retrying, are not logged
+        if bad_areas_before == 0 {
+            self.log_event("bad", &format!("0x{:08X}  0x{:08X}", range.start, range.end - range.start));
+        }
+        if let Some(ref mut tui) = self.tui {
+            tui.record_error(range);
+        }
+    }
+
+    fn mark_rescued(&mut self, rescued: Range<u64>, phase_target: &SectorState) -> io::Result<()> {
+        // Bad areas only shrink or split while they are being retried
+        let bad_areas_before = if *phase_target == SectorState::Bad { self.count_bad_areas_around(&rescued) } else { 0 };
+        self.update_histogram(rescued.end - rescued.start, *phase_target, SectorState::Rescued);
+        self.map_file.put(rescued.clone(), SectorState::Rescued);
+        if *phase_target == SectorState::Bad {
+            self.bad_areas = self.bad_areas - bad_areas_before + self.count_bad_areas_around(&rescued);
+        }
+        if let Some(ref mut reconstructed) = self.reconstructed {
+            // Any earlier reconstruction has been superseded
+            reconstructed.put(rescued.clone(), SectorState::Untried);
+        }
+        match self.image_hash {
+            Some(ref mut hash) => hash.update(&self.map_file, rescued, &mut self.output),
+            None => Ok(()),
+        }
+    }
+
+    fn do_pass(&mut self, phase_target: &SectorState) -> Result<(), Box<Error>> {
+        let mut pass_complete = false;
+        while !pass_complete && self.should_run() && !self.skip_phase {
+            let mut reads: VecDeque<Range<u64>> =
+                (&self.map_file).iter_range(self.map_file.get_pos()..self.map_file.get_size())
+                .filter(|r| r.tag == *phase_target)
+                .flat_map(|r| range_to_reads(&r.as_range(), &self.block))
+                .take(READ_BATCH_SIZE).collect();
+
+            pass_complete = reads.is_empty();
+            while !reads.is_empty() && self.should_run() && !self.skip_phase {
+                self.handle_requests()?;
+                if self.paused {
+                    self.wait_while_paused(phase_target)?;
+                    continue;
+                }
+                if self.skip_area {
+                    self.skip_current_area(phase_target);
+                    reads.clear();
+                    break;
+                }
+                if self.can_submit() {
+                    // Rereads for confirmation take priority so that their data need not be held for long
+                    let read = match self.confirm_queue.pop_front() {
+                        Some(read) => read,
+                        None => reads.pop_front().unwrap(),
+                    };
+                    let buffer = self.get_cleared_buffer();
+                    let request = Request::new(read.start, read.end - read.start, buffer);
+                    self.block.submit_request(request).map_err(RecoverError::Aio)?;
+                    let current_start = self.map_file.get_pos();
+                    self.map_file.set_pos(cmp::max(current_start, read.end));
+                }
+                if !self.can_submit() {
+                    self.try_drain_request(phase_target)?;
+                    self.update_status();
+                }
+                let now = Instant::now();
+                if now.duration_since(self.last_sync.clone()).as_secs() >= SYNC_INTERVAL as u64 {
+                    self.do_sync()?;
+                }
+            }
+        }
+        loop {
+            self.handle_requests()?;
+            if !self.confirm_queue.is_empty() && self.should_run() && !self.skip_phase && self.can_submit() {
+                let read = self.confirm_queue.pop_front().unwrap();
+                let buffer = self.get_cleared_buffer();
+                self.block.submit_request(Request::new(read.start, read.end - read.start, buffer)).map_err(RecoverError::Aio)?;
+            } else if self.block.requests_pending() > 0 {
+                self.try_drain_request(phase_target)?;
+                self.update_status();
+            } else {
+                break;
+            }
+        }
+        // Ranges left unconfirmed after an interruption remain in their previous state
+        self.confirm_queue.clear();
+        self.confirmations.clear();
+        Ok(())
+    }
+
+    // Rereads every rescued region of the input and compares it with the output
+    pub fn do_verify(&mut self) -> Result<(), Box<Error>> {
+        let mut failures = TaggedRange::new();
+        let mut verified = 0;
+        // The end of the reads generated so far, which are generated a batch at a time as in do_pass
+        let mut generated = 0;
+        // The end of the reads completed so far
+        let mut pos = 0;
+        let mut reads: VecDeque<Range<u64>> = VecDeque::new();
+        loop {
+            if reads.is_empty() && generated < self.map_file.get_size() {
+                reads = (&self.map_file).iter_range(generated..self.map_file.get_size())
+                    .filter(|r| r.tag == SectorState::Rescued)
+                    .flat_map(|r| range_to_reads(&r.as_range(), &self.block))
+                    .take(READ_BATCH_SIZE).collect();
+                generated = reads.back().map_or(self.map_file.get_size(), |r| r.end);
+            }
+            if (reads.is_empty() && self.block.requests_pending() == 0) || !self.should_run() {
+                break;
+            }
+            if !reads.is_empty() && self.block.requests_avail() > 0 {
+                let read = reads.pop_front().unwrap();
+                let buffer = self.get_cleared_buffer();
+                self.block.submit_request(Request::new(read.start, read.end - read.start, buffer)).map_err(RecoverError::Aio)?;
+            } else {
+                let request = match self.block.get_completed_request() {
+                    Ok(r) => r,
+                    Err(nix::Error::Sys(nix::Errno::EINTR)) => continue,
+                    Err(err) => return Err(Box::new(RecoverError::Aio(err))),
+                };
+                verified += self.verify_request(&request, &mut failures).map_err(RecoverError::output)?;
+                pos = cmp::max(pos, request.offset + request.size);
+                self.recycle_buffer(request.reclaim_buffer());
+                let status = self.get_verify_status(pos, verified, &failures);
+                self.update_verify_status(&status);
+            }
+        }
+        self.abandon_requests().map_err(RecoverError::Aio)?;
+
+        let status = self.get_verify_status(pos, verified, &failures);
+        let reset = self.settings.verify_reset && status.mismatched > 0;
+        if reset {
+            for region in failures.iter().filter(|r| r.tag == VerifyFailure::Mismatch) {
+                self.update_histogram(region.length, SectorState::Rescued, SectorState::Untried);
+                self.map_file.put(region.as_range(), SectorState::Untried);
+            }
+            // Non-tried regions are only read during the copying phase
+            self.map_file.set_phase(&Phase::Copying);
+            self.map_file.set_pos(0);
+            if let Some(ref mut hash) = self.image_hash {
+                hash.reconcile(&self.map_file);
+            }
+            self.write_map()?;
+        }
+        self.print_verify_summary(&status, &failures, reset);
+        if status.mismatched > 0 {
+            return Err(Box::new(RecoverError::VerifyMismatch(status.mismatched)));
+        }
+        Ok(())
+    }
+
+    fn get_verify_status(&self, pos: u64, verified: u64, failures: &TaggedRange<VerifyFailure>) -> VerifyStatus {
+        let failed = |failure| failures.iter().filter(|r| r.tag == failure).map(|r| r.length).sum();
+        VerifyStatus {
+            pos: pos,
+            size: self.map_file.get_size(),
+            verified: verified,
+            mismatched: failed(VerifyFailure::Mismatch),
+            unreadable: failed(VerifyFailure::Unreadable),
+            elapsed: self.start.elapsed().as_secs(),
+        }
+    }
+
+    // Prints the progress of a verification at the same intervals as the status of a rescue
+    fn update_verify_status(&mut self, status: &VerifyStatus) {
+        if self.settings.quiet {
+            return;
+        }
+        let now = Instant::now();
+        let overwrite = match self.last_print {
+            None => false,
+            Some(previous) => {
+                let duration = now.duration_since(previous);
+                let seconds = duration.as_secs() as f32 + duration.subsec_nanos() as f32 * 1e-9;
+                if seconds <= self.settings.progress_interval {
+                    return;
+                }
+                true
+            },
+        };
+        match self.settings.status_format {
+            // There is no full-screen display for verification, so a single line is redrawn instead
+            StatusFormat::Text | StatusFormat::Tui => {
+                if overwrite {
+                    print!("{}{}", ansi_escapes::CursorLeft, ansi_escapes::CursorUp(1));
+                }
+                println!("{}{}", self.format_verify_line(status), ansi_escapes::EraseEndLine);
+            },
+            StatusFormat::Plain => println!("{}", self.format_verify_line(status)),
+            StatusFormat::Json => println!("{}", status.to_json("verify", &[])),
+        }
+        self.last_print = Some(now);
+    }
+
+    fn format_verify_line(&self, status: &VerifyStatus) -> String {
+        format!("[{}] Verifying: ipos {}, verified {}, mismatched {}, unreadable {}",
+                status::format_seconds(status.elapsed),
+                self.format_bytes_with_percentage(status.pos),
+                status::format_bytes(status.verified),
+                status::format_bytes(status.mismatched),
+                status::format_bytes(status.unreadable))
+    }
+
+    // Lists the regions which failed verification, followed by the totals
+    fn print_verify_summary(&self, status: &VerifyStatus, failures: &TaggedRange<VerifyFailure>, reset: bool) {
+        if self.settings.status_format == StatusFormat::Json {
+            for region in failures.iter() {
+                let reason = match region.tag {
+                    VerifyFailure::Mismatch => "mismatch",
+                    VerifyFailure::Unreadable => "unreadable",
+                };
+                println!("{}", status::region_to_json("verify_failure", region.start, region.length, reason));
+            }
+            let extra = [
+                ("interrupted", JsonValue::Bool(!self.should_run())),
+                ("marked_non_tried", JsonValue::Number(if reset { status.mismatched } else { 0 })),
+            ];
+            println!("{}", status.to_json("verify_summary", &extra));
+            return;
+        }
+        for region in failures.iter() {
+            let description = match region.tag {
+                VerifyFailure::Mismatch => "differs from input",
+                VerifyFailure::Unreadable => "unreadable",
+            };
+            println!("0x{:08X}  0x{:08X}  {}", region.start, region.length, description);
+        }
+        println!("Verified {}, mismatched {}, unreadable {}{}",
+                 status::format_bytes(status.verified), status::format_bytes(status.mismatched),
+                 status::format_bytes(status.unreadable), if self.should_run() { "" } else { " (interrupted)" });
+        if reset {
+            println!("Marked {} as non-tried.", status::format_bytes(status.mismatched));
+        }
+    }
+
+    // Compares the rescued parts of a completed read with the output, returning the number of
+    // bytes compared
+    fn verify_request(&mut self, request: &Request, failures: &mut TaggedRange<VerifyFailure>) -> io::Result<u64> {
+        let requested = request.offset..(request.offset + request.size);
+        let data = request.get_data();
+        let read_end = request.offset + data.len() as u64;
+        // Only the rescued parts of a short read were expected to be readable
+        let unreadable: Vec<Range<u64>> = self.map_file.iter_range(read_end..requested.end)
+            .filter(|r| r.tag == SectorState::Rescued && r.length > 0)
+            .map(|r| r.as_range())
+            .collect();
+        for region in unreadable {
+            failures.put(region, VerifyFailure::Unreadable);
+        }
+        let regions: Vec<Range<u64>> = self.map_file.iter_range(request.offset..read_end)
+            .filter(|r| r.tag == SectorState::Rescued && r.length > 0)
+            .map(|r| r.as_range())
+            .collect();
+        // An aligned buffer is required to read from an O_DIRECT output
+        let mut output = self.get_cleared_buffer();
+        let mut compared = 0;
+        for region in regions {
+            let within = (region.start - request.offset) as usize..(region.end - request.offset) as usize;
+            let result = self.output.read_at(region.start, &mut output.as_mut_slice()[within.clone()]);
+            if let Err(err) = result {
+                self.recycle_buffer(output);
+                return Err(err);
+            }
+            // Regions of the map start and end on sector boundaries
+            let sector_size = self.block.get_sector_size();
+            let sectors = output.as_slice()[within.clone()].chunks(sector_size).zip(data[within].chunks(sector_size));
+            for (index, (written, read)) in sectors.enumerate() {
+                if written != read {
+                    let start = region.start + (index * sector_size) as u64;
+                    failures.put(start..(start + written.len() as u64), VerifyFailure::Mismatch);
+                }
+            }
+            compared += region.end - region.start;
+        }
+        self.recycle_buffer(output);
+        Ok(compared)
+    }
+}
+
+struct ReadIter {
+    start: u64,
+    end: u64,
+    physical_block_size: usize,
+}
+
+impl Iterator for ReadIter {
+    type Item = Range<u64>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let physical_block_size = self.physical_block_size as u64;
+        if self.start < self.end {
+            let read_end = cmp::min(((self.start + physical_block_size) / physical_block_size) * physical_block_size, self.end);
+            let result = self.start..read_end;
+            self.start = read_end;
+            Some(result)
+        } else {
+            None
+        }
+    }
+}
+
+fn range_to_reads<I: Input>(range: &Range<u64>, block: &I) -> ReadIter {
+    let sector_size = block.get_sector_size();
+    let physical_block_size = block.get_block_size_physical();
+    let size_bytes = block.get_size_bytes();
+    assert!(physical_block_size % sector_size == 0);
+
+    let sector_size_u64 = sector_size as u64;
+    let start = (range.start / sector_size_u64) * sector_size_u64;
+    let end = cmp::min(((range.end + sector_size_u64 - 1) / sector_size_u64) * sector_size_u64, size_bytes);
+    ReadIter {
+        start: start,
+        end: end,
+        physical_block_size: physical_block_size,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use phase::Phase;
+    use std::fs::{self, File};
+    use testing::{noise, test_dir, MemoryInput, MemorySink};
+
+    const SIZE: u64 = 0x100000;
+    const SECTOR_SIZE: usize = 512;
+    const BLOCK_SIZE: usize = 4096;
+
+    fn quiet_settings() -> Settings {
+        let mut settings = Settings::new();
+        settings.quiet = true;
+        settings
+    }
+
+    fn read_map(path: &Path) -> MapFile {
+        MapFile::read_from_stream(File::open(path).unwrap()).unwrap()
+    }
+
+    fn states(map: &MapFile) -> Vec<(Range<u64>, SectorState)> {
+        map.iter().map(|r| (r.as_range(), r.tag)).collect()
+    }
+
+    #[test]
+    fn clean_rescue_copies_everything() {
+        let dir = test_dir("recover-clean");
+        let map_path = dir.join("drive.map");
+        let data = noise(1, SIZE as usize);
+        let input = MemoryInput::new(data.clone(), SECTOR_SIZE, BLOCK_SIZE);
+        let mut recover = Recover::new(input, MemorySink::new(SIZE), map_path.to_str().unwrap(), quiet_settings()).unwrap();
+        recover.run_phases().unwrap();
+
+        assert!(recover.output.get_data() == &data[..]);
+        assert!(recover.output.get_sync_count() > 0);
+        let map = read_map(&map_path);
+        assert_eq!(map.get_phase(), Phase::Finished);
+        assert_eq!(states(&map), vec![(0..SIZE, SectorState::Rescued)]);
+        // Every sector is read exactly once
+        let read: u64 = recover.block.get_reads().iter().map(|r| r.end - r.start).sum();
+        assert_eq!(read, SIZE);
+    }
+
+    // The writes expected from reading `range` a physical block at a time
+    fn block_writes(data: &[u8], range: Range<u64>) -> Vec<(u64, Vec<u8>)> {
+        let input = MemoryInput::new(data.to_vec(), SECTOR_SIZE, BLOCK_SIZE);
+        range_to_reads(&range, &input)
+            .map(|r| (r.start, data[(r.start as usize)..(r.end as usize)].to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn each_phase_writes_what_it_reads() {
+        let dir = test_dir("recover-writes");
+        let map_path = dir.join("drive.map");
+        let mut data = noise(1, SIZE as usize);
+        // A block of zeros is written as such
+        for value in &mut data[0x1000..0x2000] {
+            *value = 0;
+        }
+        let mut map = MapFile::new(SIZE);
+        map.put(0..0x40000, SectorState::Untried);
+        map.put(0x40000..0x80200, SectorState::Rescued);
+        map.put(0x80200..0x80a00, SectorState::Untrimmed);
+        map.put(0x80a00..0xc0400, SectorState::Rescued);
+        map.put(0xc0400..0xc0600, SectorState::Unscraped);
+        map.put(0xc0600..SIZE, SectorState::Rescued);
+        map.write_to_path(&map_path).unwrap();
+
+        let input = MemoryInput::new(data.clone(), SECTOR_SIZE, BLOCK_SIZE);
+        let mut recover = Recover::new(input, MemorySink::new(SIZE), map_path.to_str().unwrap(), quiet_settings()).unwrap();
+        recover.run_phases().unwrap();
+
+        // Copying, then trimming, then scraping, each in order of offset and never straying from
+        // the regions of the map they target
+        let mut expected = block_writes(&data, 0..0x40000);
+        expected.push((0x80200, data[0x80200..0x80a00].to_vec()));
+        expected.push((0xc0400, data[0xc0400..0xc0600].to_vec()));
+        assert_eq!(expected.len(), 0x40 + 2);
+        assert!(recover.output.get_writes() == &expected[..]);
+        assert!(expected[1].1.iter().all(|v| *v == 0));
+    }
+
+    #[test]
+    fn failing_sectors_are_retried() {
+        let dir = test_dir("recover-failing");
+        let map_path = dir.join("drive.map");
+        let data = noise(1, SIZE as usize);
+        let bad = 0x21000..0x21400;
+        let mut input = MemoryInput::new(data.clone(), SECTOR_SIZE, BLOCK_SIZE);
+        input.set_bad(bad.clone(), 6);
+        let mut recover = Recover::new(input, MemorySink::new(SIZE), map_path.to_str().unwrap(), quiet_settings()).unwrap();
+        recover.run_phases().unwrap();
+
+        assert!(recover.output.get_data() == &data[..]);
+        let map = read_map(&map_path);
+        assert_eq!(map.get_phase(), Phase::Finished);
+        assert_eq!(states(&map), vec![(0..SIZE, SectorState::Rescued)]);
+        let bad_reads = recover.block.get_reads().iter()
+            .filter(|r| r.start < bad.end && bad.start < r.end)
+            .count();
+        assert!(bad_reads > 6);
+    }
+
+    #[test]
+    fn resume_reads_only_what_is_left() {
+        let dir = test_dir("recover-resume");
+        let map_path = dir.join("drive.map");
+        let data = noise(1, SIZE as usize);
+        let mut map = MapFile::new(SIZE);
+        map.put(0..(SIZE / 2), SectorState::Rescued);
+        map.write_to_path(&map_path).unwrap();
+
+        let input = MemoryInput::new(data.clone(), SECTOR_SIZE, BLOCK_SIZE);
+        let mut recover = Recover::new(input, MemorySink::new(SIZE), map_path.to_str().unwrap(), quiet_settings()).unwrap();
+        recover.run_phases().unwrap();
+
+        assert!(recover.block.get_reads().iter().all(|r| r.start >= SIZE / 2));
+        let output = recover.output.get_data();
+        assert!(output[..(SIZE / 2) as usize].iter().all(|v| *v == 0));
+        assert!(&output[(SIZE / 2) as usize..] == &data[(SIZE / 2) as usize..]);
+        assert_eq!(states(&read_map(&map_path)), vec![(0..SIZE, SectorState::Rescued)]);
+    }
+
+    #[test]
+    fn resumed_rescue_hashes_what_was_already_rescued() {
+        let dir = test_dir("recover-hash");
+        let map_path = dir.join("drive.map");
+        let data = noise(1, SIZE as usize);
+        let mut map = MapFile::new(SIZE);
+        map.put(0..(SIZE / 2), SectorState::Rescued);
+        map.write_to_path(&map_path).unwrap();
+        let mut output = MemorySink::new(SIZE);
+        output.write_at(0, &data[..(SIZE / 2) as usize]).unwrap();
+
+        let mut settings = quiet_settings();
+        settings.hash = true;
+        let input = MemoryInput::new(data.clone(), SECTOR_SIZE, BLOCK_SIZE);
+        let mut recover = Recover::new(input, output, map_path.to_str().unwrap(), settings).unwrap();
+        recover.run_phases().unwrap();
+
+        let mut hasher = sha256::Sha256::new();
+        hasher.update(&data);
+        let digest = recover.image_hash.as_ref().and_then(|h| h.get_image_digest());
+        assert_eq!(digest, Some(hasher.finish()));
+        let saved = ImageHash::read_from_path(&ImageHash::get_path(&map_path), SIZE).unwrap().unwrap();
+        assert_eq!(saved.get_image_digest(), digest);
+    }
+
+    // A map in which only `range` is left, to be scraped
+    fn write_unscraped_map(path: &Path, range: Range<u64>) {
+        let mut map = MapFile::new(SIZE);
+        map.put(0..SIZE, SectorState::Rescued);
+        map.put(range, SectorState::Unscraped);
+        map.set_phase(&Phase::Scraping);
+        map.write_to_path(path).unwrap();
+    }
+
+    #[test]
+    fn confirm_reads_count_unstable_sectors() {
+        let dir = test_dir("recover-unstable");
+        let map_path = dir.join("drive.map");
+        write_unscraped_map(&map_path, 0x40000..0x41000);
+        let data = noise(1, SIZE as usize);
+        let mut input = MemoryInput::new(data.clone(), SECTOR_SIZE, BLOCK_SIZE);
+        // The first read of one sector returns other data, and the reads after it agree
+        input.set_unstable(0x40200..0x40400, vec![noise(2, SECTOR_SIZE)]);
+
+        let mut settings = quiet_settings();
+        settings.confirm_reads = 2;
+        let mut recover = Recover::new(input, MemorySink::new(SIZE), map_path.to_str().unwrap(), settings).unwrap();
+        recover.run_phases().unwrap();
+
+        // The disagreeing reads mark the block as bad, and it is retried until two reads agree
+        assert_eq!(recover.stats.unstable, SECTOR_SIZE as u64);
+        assert_eq!(recover.block.get_reads(), &[
+            0x40000..0x41000, 0x40000..0x41000,
+            0x40000..0x41000, 0x40000..0x41000,
+        ]);
+        assert!(recover.output.get_data()[0x40000..0x41000] == data[0x40000..0x41000]);
+        assert_eq!(states(&read_map(&map_path)), vec![(0..SIZE, SectorState::Rescued)]);
+        assert!(fs::metadata(dir.join("drive.map.unstable")).is_ok());
+    }
+
+    #[test]
+    fn vote_reads_reconstruct_the_majority() {
+        let dir = test_dir("recover-vote");
+        let map_path = dir.join("drive.map");
+        write_unscraped_map(&map_path, 0x40000..0x41000);
+        let data = noise(1, SIZE as usize);
+        let sector = 0x40200..0x40400;
+        // Every read of the sector is wrong in a third of its bytes, a different third each time
+        let variants = (0..3).map(|i| {
+            let mut variant = data[(sector.start as usize)..(sector.end as usize)].to_vec();
+            for idx in (i..SECTOR_SIZE).step_by(3) {
+                variant[idx] ^= 0xFF;
+            }
+            variant
+        }).collect();
+        let mut input = MemoryInput::new(data.clone(), SECTOR_SIZE, BLOCK_SIZE);
+        input.set_unstable(sector.clone(), variants);
+
+        let mut settings = quiet_settings();
+        settings.confirm_reads = 2;
+        settings.vote_reads = 3;
+        let mut recover = Recover::new(input, MemorySink::new(SIZE), map_path.to_str().unwrap(), settings).unwrap();
+        recover.run_phases().unwrap();
+
+        assert_eq!(recover.block.get_reads(), &[0x40000..0x41000, 0x40000..0x41000, 0x40000..0x41000]);
+        assert!(recover.output.get_data()[0x40000..0x41000] == data[0x40000..0x41000]);
+        assert_eq!(recover.stats.reconstructed, BLOCK_SIZE as u64);
+        assert_eq!(states(&read_map(&map_path)), vec![(0..SIZE, SectorState::Rescued)]);
+        let reconstructed = read_map(&dir.join("drive.map.reconstructed"));
+        assert_eq!(states(&reconstructed), vec![
+            (0..0x40000, SectorState::Untried),
+            (0x40000..0x41000, SectorState::Rescued),
+            (0x41000..SIZE, SectorState::Untried),
+        ]);
+    }
+
+    #[test]
+    fn verify_reset_rereads_mismatches() {
+        let dir = test_dir("recover-verify");
+        let map_path = dir.join("drive.map");
+        let data = noise(1, SIZE as usize);
+        let mut map = MapFile::new(SIZE);
+        map.put(0..SIZE, SectorState::Rescued);
+        map.set_phase(&Phase::Finished);
+        map.write_to_path(&map_path).unwrap();
+        let mut output = MemorySink::new(SIZE);
+        output.write_at(0, &data).unwrap();
+        output.write_at(0x40200, &[0u8; 0x200]).unwrap();
+
+        let mut settings = quiet_settings();
+        settings.verify = true;
+        settings.verify_reset = true;
+        let input = MemoryInput::new(data.clone(), SECTOR_SIZE, BLOCK_SIZE);
+        let mut recover = Recover::new(input, output, map_path.to_str().unwrap(), settings).unwrap();
+        let err = RecoverError::from(recover.do_verify().unwrap_err());
+        assert_eq!(err.exit_code(), 8);
+
+        // Only the sectors which differ are marked, not the rest of the block read
+        let map = read_map(&map_path);
+        assert_eq!(map.get_phase(), Phase::Copying);
+        assert_eq!(states(&map), vec![
+            (0..0x40200, SectorState::Rescued),
+            (0x40200..0x40400, SectorState::Untried),
+            (0x40400..SIZE, SectorState::Rescued),
+        ]);
+
+        // The mismatched sector is all that a further run reads
+        let output = recover.output;
+        let input = MemoryInput::new(data.clone(), SECTOR_SIZE, BLOCK_SIZE);
+        let mut recover = Recover::new(input, output, map_path.to_str().unwrap(), quiet_settings()).unwrap();
+        recover.run_phases().unwrap();
+        assert_eq!(recover.block.get_reads(), &[0x40200..0x40400]);
+        assert!(recover.output.get_data() == &data[..]);
+    }
+}
@@ -0,0 +1,236 @@
+use std::cmp;
+
+// An implementation of SHA-256 as specified in FIPS 180-2. The intermediate state can be
+// extracted and restored on a block boundary, so that a digest of a large image can be computed
+// across several runs.
+
+pub const DIGEST_SIZE: usize = 32;
+pub const BLOCK_SIZE: usize = 64;
+
+const INITIAL_STATE: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+pub type Digest = [u8; DIGEST_SIZE];
+
+#[derive(Clone, Debug)]
+pub struct Sha256 {
+    state: [u32; 8],
+    // Total number of bytes processed, including those pending in the buffer
+    length: u64,
+    buffer: [u8; BLOCK_SIZE],
+    buffered: usize,
+}
+
+impl Sha256 {
+    pub fn new() -> Sha256 {
+        Sha256 {
+            state: INITIAL_STATE,
+            length: 0,
+            buffer: [0; BLOCK_SIZE],
+            buffered: 0,
+        }
+    }
+
+    // Resumes a computation from a state previously returned by get_state()
+    pub fn from_state(state: [u32; 8], length: u64) -> Sha256 {
+        assert!(length % BLOCK_SIZE as u64 == 0, "SHA-256 state must be on a block boundary");
+        Sha256 {
+            state: state,
+            length: length,
+            buffer: [0; BLOCK_SIZE],
+            buffered: 0,
+        }
+    }
+
+    // Only available once a whole number of blocks has been processed
+    pub fn get_state(&self) -> Option<([u32; 8], u64)> {
+        if self.buffered == 0 {
+            Some((self.state, self.length))
+        } else {
+            None
+        }
+    }
+
+    pub fn get_length(&self) -> u64 {
+        self.length
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+        if self.buffered > 0 {
+            let count = cmp::min(BLOCK_SIZE - self.buffered, data.len());
+            self.buffer[self.buffered..(self.buffered + count)].copy_from_slice(&data[..count]);
+            self.buffered += count;
+            data = &data[count..];
+            if self.buffered < BLOCK_SIZE {
+                return;
+            }
+            let block = self.buffer;
+            self.compress(&block);
+            self.buffered = 0;
+        }
+        while data.len() >= BLOCK_SIZE {
+            self.compress(&data[..BLOCK_SIZE]);
+            data = &data[BLOCK_SIZE..];
+        }
+        self.buffer[..data.len()].copy_from_slice(data);
+        self.buffered = data.len();
+    }
+
+    pub fn finish(mut self) -> Digest {
+        let bit_length = self.length.wrapping_mul(8);
+        let mut padding = [0u8; BLOCK_SIZE + 8];
+        padding[0] = 0x80;
+        let padding_length = if self.buffered < 56 { 56 - self.buffered } else { 120 - self.buffered };
+        for idx in 0..8 {
+            padding[padding_length + idx] = (bit_length >> (56 - idx * 8)) as u8;
+        }
+        let length = self.length;
+        self.update(&padding[..(padding_length + 8)]);
+        debug_assert!(self.buffered == 0);
+        self.length = length;
+
+        let mut digest = [0u8; DIGEST_SIZE];
+        for (idx, word) in self.state.iter().enumerate() {
+            for byte in 0..4 {
+                digest[idx * 4 + byte] = (word >> (24 - byte * 8)) as u8;
+            }
+        }
+        digest
+    }
+
+    fn compress(&mut self, block: &[u8]) {
+        let mut w = [0u32; 64];
+        for idx in 0..16 {
+            w[idx] = (block[idx * 4] as u32) << 24 | (block[idx * 4 + 1] as u32) << 16
+                | (block[idx * 4 + 2] as u32) << 8 | block[idx * 4 + 3] as u32;
+        }
+        for idx in 16..64 {
+            let s0 = w[idx - 15].rotate_right(7) ^ w[idx - 15].rotate_right(18) ^ (w[idx - 15] >> 3);
+            let s1 = w[idx - 2].rotate_right(17) ^ w[idx - 2].rotate_right(19) ^ (w[idx - 2] >> 10);
+            w[idx] = w[idx - 16].wrapping_add(s0).wrapping_add(w[idx - 7]).wrapping_add(s1);
+        }
+
+        let mut v = self.state;
+        for idx in 0..64 {
+            let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+            let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+            let t1 = v[7].wrapping_add(s1).wrapping_add(ch).wrapping_add(K[idx]).wrapping_add(w[idx]);
+            let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+            let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+            let t2 = s0.wrapping_add(maj);
+            v[7] = v[6];
+            v[6] = v[5];
+            v[5] = v[4];
+            v[4] = v[3].wrapping_add(t1);
+            v[3] = v[2];
+            v[2] = v[1];
+            v[1] = v[0];
+            v[0] = t1.wrapping_add(t2);
+        }
+        for idx in 0..8 {
+            self.state[idx] = self.state[idx].wrapping_add(v[idx]);
+        }
+    }
+}
+
+pub fn to_hex(data: &[u8]) -> String {
+    let mut result = String::with_capacity(data.len() * 2);
+    for byte in data {
+        result.push_str(&format!("{:02x}", byte));
+    }
+    result
+}
+
+pub fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 || !text.chars().all(|c| c.is_digit(16)) {
+        return None;
+    }
+    let mut result = Vec::with_capacity(text.len() / 2);
+    for idx in 0..(text.len() / 2) {
+        match u8::from_str_radix(&text[(idx * 2)..(idx * 2 + 2)], 16) {
+            Ok(value) => result.push(value),
+            Err(_) => return None,
+        }
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest_hex(data: &[u8]) -> String {
+        let mut hash = Sha256::new();
+        hash.update(data);
+        to_hex(&hash.finish())
+    }
+
+    // Test vectors from FIPS 180-2, appendix B
+    #[test]
+    fn one_block_message() {
+        assert_eq!(digest_hex(b"abc"),
+                   "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+
+    #[test]
+    fn multi_block_message() {
+        assert_eq!(digest_hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+                   "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+    }
+
+    #[test]
+    fn long_message() {
+        let mut hash = Sha256::new();
+        let chunk = [b'a'; 1000];
+        for _ in 0..1000 {
+            hash.update(&chunk);
+        }
+        assert_eq!(to_hex(&hash.finish()),
+                   "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0");
+    }
+
+    #[test]
+    fn empty_message() {
+        assert_eq!(digest_hex(b""),
+                   "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    }
+
+    #[test]
+    fn resume_from_state() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+        let mut first = Sha256::new();
+        // Updates which do not end on a block boundary leave no state to save
+        first.update(&data[..100]);
+        assert!(first.get_state().is_none());
+        first.update(&data[100..(3 * BLOCK_SIZE)]);
+        let (state, length) = first.get_state().unwrap();
+        assert_eq!(length, 3 * BLOCK_SIZE as u64);
+
+        let mut resumed = Sha256::from_state(state, length);
+        resumed.update(&data[(3 * BLOCK_SIZE)..]);
+        assert_eq!(resumed.get_length(), data.len() as u64);
+        assert_eq!(to_hex(&resumed.finish()), digest_hex(&data));
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        let data = [0x00, 0x7f, 0xa5, 0xff];
+        assert_eq!(to_hex(&data), "007fa5ff");
+        assert_eq!(from_hex("007fa5ff"), Some(data.to_vec()));
+        assert_eq!(from_hex("007"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+}
@@ -0,0 +1,66 @@
+use libc::c_int;
+use nix;
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal, SA_RESETHAND};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+// Handling of the signals which control a rescue. The handlers only record which signals have
+// arrived, for the rescue to act on between reads.
+//
+// SIGINT, SIGTERM and SIGHUP stop the rescue gracefully. Their handlers are reset once they have
+// run, so that a second such signal terminates the process immediately if stopping gracefully
+// takes too long. Any of them which was ignored when the process started, as SIGHUP is under
+// nohup, is left ignored. SIGUSR1 requests an immediate sync of the output and map, and SIGUSR2 a
+// dump of the status.
+//
+// Signals interrupt waits for reads to complete rather than restarting them, so that they are
+// acted on promptly even when the device is slow to respond.
+
+static INTERRUPTING_SIGNAL: AtomicUsize = AtomicUsize::new(0);
+static SYNC_REQUESTED: AtomicBool = AtomicBool::new(false);
+static DUMP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_signal(signum: c_int) {
+    match Signal::from_c_int(signum) {
+        Ok(Signal::SIGUSR1) => SYNC_REQUESTED.store(true, Ordering::SeqCst),
+        Ok(Signal::SIGUSR2) => DUMP_REQUESTED.store(true, Ordering::SeqCst),
+        _ => INTERRUPTING_SIGNAL.store(signum as usize, Ordering::SeqCst),
+    }
+}
+
+pub fn install() -> nix::Result<()> {
+    let interrupting = SigAction::new(SigHandler::Handler(handle_signal), SA_RESETHAND, SigSet::empty());
+    let requesting = SigAction::new(SigHandler::Handler(handle_signal), SaFlags::empty(), SigSet::empty());
+    unsafe {
+        for signal in [Signal::SIGINT, Signal::SIGTERM, Signal::SIGHUP].iter() {
+            let previous = signal::sigaction(*signal, &interrupting)?;
+            if previous.handler() == SigHandler::SigIgn {
+                signal::sigaction(*signal, &previous)?;
+            }
+        }
+        signal::sigaction(Signal::SIGUSR1, &requesting)?;
+        signal::sigaction(Signal::SIGUSR2, &requesting)?;
+    }
+    Ok(())
+}
+
+// The signal which asked the rescue to stop, if any
+pub fn get_interrupting_signal() -> Option<Signal> {
+    match INTERRUPTING_SIGNAL.load(Ordering::SeqCst) {
+        0 => None,
+        signum => Signal::from_c_int(signum as c_int).ok(),
+    }
+}
+
+pub fn is_interrupted() -> bool {
+    INTERRUPTING_SIGNAL.load(Ordering::SeqCst) != 0
+}
+
+// Returns whether a sync was requested since the last call
+pub fn take_sync_request() -> bool {
+    SYNC_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+// Returns whether a status dump was requested since the last call
+pub fn take_dump_request() -> bool {
+    DUMP_REQUESTED.swap(false, Ordering::SeqCst)
+}
@@ -0,0 +1,42 @@
+use std::io;
+use std::ops::Range;
+use std::os::unix::io::RawFd;
+
+// A destination for rescued data. Offsets are those of the input device, so a sink must be able
+// to hold get_size_bytes() bytes regardless of how they are stored.
+pub trait Sink {
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()>;
+
+    // The range must read back as zeros afterwards, whatever it previously contained
+    fn write_zeros(&mut self, range: Range<u64>) -> io::Result<()>;
+
+    fn read_at(&mut self, offset: u64, data: &mut [u8]) -> io::Result<()>;
+
+    fn is_range_zero(&mut self, range: Range<u64>) -> io::Result<bool>;
+
+    // Once this returns, everything previously written must survive a crash
+    fn sync(&mut self) -> io::Result<()>;
+
+    fn get_size_bytes(&self) -> io::Result<u64>;
+
+    fn get_allocated_bytes(&self) -> io::Result<u64>;
+
+    // Whether zero-filled areas take up no space
+    fn is_sparse(&self) -> bool {
+        true
+    }
+
+    // A descriptor to which data can be written at its final location using AIO
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    // Stores a serialized map alongside the data, for sinks which are able to carry one
+    fn embed_map(&mut self, _data: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read_embedded_map(&mut self) -> io::Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+}
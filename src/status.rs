@@ -0,0 +1,230 @@
+use phase::Phase;
+use std::fmt::Write;
+
+// A snapshot of the progress of a rescue, from which the various status displays are produced.
+// Durations are in whole seconds and sizes in bytes.
+#[derive(Clone, Debug)]
+pub struct Status {
+    pub phase: Phase,
+    pub pass: usize,
+    pub pos: u64,
+    pub size: u64,
+    pub rescued: u64,
+    pub bad: u64,
+    pub untried: u64,
+    pub untrimmed: u64,
+    pub unscraped: u64,
+    // Bytes read successfully and unsuccessfully during this run
+    pub read_bytes: u64,
+    pub error_bytes: u64,
+    pub unstable: u64,
+    pub reconstructed: u64,
+    // Bytes whose most recent read took a second or more, if latency is tracked
+    pub slow: Option<u64>,
+    pub allocated: Option<u64>,
+    pub apparent_size: Option<u64>,
+    pub sparse: bool,
+    pub elapsed: u64,
+    // Time since the last successful read, if there has been one
+    pub since_success: Option<u64>,
+}
+
+impl Status {
+    pub fn total_bytes(&self) -> u64 {
+        self.read_bytes + self.error_bytes
+    }
+
+    pub fn remaining_bytes(&self) -> u64 {
+        self.untried + self.untrimmed + self.unscraped
+    }
+
+    // Estimated from the rate at which regions have been tried during this run
+    pub fn remaining_seconds(&self) -> u64 {
+        let total = self.total_bytes();
+        if total > 0 {
+            self.remaining_bytes() * self.elapsed / total
+        } else {
+            0
+        }
+    }
+
+    // Undefined until a second has elapsed
+    pub fn rate(&self, bytes: u64) -> Option<u64> {
+        if self.elapsed > 0 {
+            Some(bytes / self.elapsed)
+        } else {
+            None
+        }
+    }
+
+    // A single-line JSON object. `kind` distinguishes periodic updates from the final summary.
+    pub fn to_json(&self, kind: &str, extra: &[(&str, JsonValue)]) -> String {
+        let phase = self.phase.name();
+        let mut object = JsonObject::new();
+        object.field("type", JsonValue::Str(kind));
+        object.field("phase", JsonValue::Str(&phase));
+        object.field("pass", JsonValue::Number(self.pass as u64));
+        object.field("pos", JsonValue::Number(self.pos));
+        object.field("size", JsonValue::Number(self.size));
+        object.field("rescued", JsonValue::Number(self.rescued));
+        object.field("bad", JsonValue::Number(self.bad));
+        object.field("non_tried", JsonValue::Number(self.untried));
+        object.field("non_trimmed", JsonValue::Number(self.untrimmed));
+        object.field("non_scraped", JsonValue::Number(self.unscraped));
+        object.field("unstable", JsonValue::Number(self.unstable));
+        object.field("reconstructed", JsonValue::Number(self.reconstructed));
+        object.field("slow", self.slow.map_or(JsonValue::Null, JsonValue::Number));
+        object.field("allocated", self.allocated.map_or(JsonValue::Null, JsonValue::Number));
+        object.field("apparent_size", self.apparent_size.map_or(JsonValue::Null, JsonValue::Number));
+        object.field("sparse", JsonValue::Bool(self.sparse));
+        object.field("read_rate", self.rate(self.read_bytes).map_or(JsonValue::Null, JsonValue::Number));
+        object.field("error_rate", self.rate(self.error_bytes).map_or(JsonValue::Null, JsonValue::Number));
+        object.field("total_rate", self.rate(self.total_bytes()).map_or(JsonValue::Null, JsonValue::Number));
+        object.field("run_time", JsonValue::Number(self.elapsed));
+        object.field("since_last_success", self.since_success.map_or(JsonValue::Null, JsonValue::Number));
+        object.field("remaining", JsonValue::Number(self.remaining_seconds()));
+        for &(name, ref value) in extra {
+            object.field(name, value.clone());
+        }
+        object.finish()
+    }
+}
+
+// A snapshot of the progress of a verification run. Sizes are in bytes and `elapsed` is in whole
+// seconds.
+#[derive(Clone, Debug)]
+pub struct VerifyStatus {
+    pub pos: u64,
+    pub size: u64,
+    pub verified: u64,
+    pub mismatched: u64,
+    pub unreadable: u64,
+    pub elapsed: u64,
+}
+
+impl VerifyStatus {
+    // A single-line JSON object, as for `Status::to_json`
+    pub fn to_json(&self, kind: &str, extra: &[(&str, JsonValue)]) -> String {
+        let mut object = JsonObject::new();
+        object.field("type", JsonValue::Str(kind));
+        object.field("pos", JsonValue::Number(self.pos));
+        object.field("size", JsonValue::Number(self.size));
+        object.field("verified", JsonValue::Number(self.verified));
+        object.field("mismatched", JsonValue::Number(self.mismatched));
+        object.field("unreadable", JsonValue::Number(self.unreadable));
+        object.field("run_time", JsonValue::Number(self.elapsed));
+        for &(name, ref value) in extra {
+            object.field(name, value.clone());
+        }
+        object.finish()
+    }
+}
+
+// A single-line JSON object describing a region of the device, e.g. one which failed verification
+pub fn region_to_json(kind: &str, pos: u64, size: u64, reason: &str) -> String {
+    let mut object = JsonObject::new();
+    object.field("type", JsonValue::Str(kind));
+    object.field("pos", JsonValue::Number(pos));
+    object.field("size", JsonValue::Number(size));
+    object.field("reason", JsonValue::Str(reason));
+    object.finish()
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    let units = ["KiB", "MiB", "GiB"];
+    let mut res_unit = "B";
+    let mut res_bytes = bytes as f64;
+    for unit in units.iter() {
+        if res_bytes >= 1000000.0 {
+            res_bytes /= 1024.0;
+            res_unit = *unit;
+        }
+    }
+    format!("{:.0} {}", res_bytes, res_unit)
+}
+
+pub fn format_rate(bytes: u64, seconds: u64) -> String {
+    if bytes == 0 || seconds > 0 {
+        let rate = if seconds > 0 {
+            bytes / seconds
+        } else {
+            0
+        };
+        format!("{}/s", format_bytes(rate))
+    } else {
+        String::from("inf")
+    }
+}
+
+pub fn format_seconds(seconds: u64) -> String {
+    let mut value = seconds;
+    let mut elements = Vec::new();
+    for &(unit, multiple) in [("s", 60), ("m", 60), ("h", 24), ("d", usize::max_value())].iter() {
+        let multiple = multiple as u64;
+        elements.push(format!("{}{}", value % multiple, unit));
+        value /= multiple;
+
+        if value == 0 {
+            break;
+        }
+    }
+    let max_time_components = 2;
+    elements.reverse();
+    elements.truncate(max_time_components);
+    elements.join(" ")
+}
+
+#[derive(Clone, Debug)]
+pub enum JsonValue<'a> {
+    Null,
+    Bool(bool),
+    Number(u64),
+    Str(&'a str),
+}
+
+struct JsonObject {
+    text: String,
+}
+
+impl JsonObject {
+    fn new() -> JsonObject {
+        JsonObject {
+            text: String::from("{"),
+        }
+    }
+
+    fn field(&mut self, name: &str, value: JsonValue) {
+        if self.text.len() > 1 {
+            self.text.push(',');
+        }
+        write_json_string(&mut self.text, name);
+        self.text.push(':');
+        match value {
+            JsonValue::Null => self.text.push_str("null"),
+            JsonValue::Bool(value) => self.text.push_str(if value { "true" } else { "false" }),
+            JsonValue::Number(value) => write!(&mut self.text, "{}", value).unwrap(),
+            JsonValue::Str(value) => write_json_string(&mut self.text, value),
+        }
+    }
+
+    fn finish(mut self) -> String {
+        self.text.push('}');
+        self.text
+    }
+}
+
+pub fn write_json_string(output: &mut String, value: &str) {
+    output.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(output, "\\u{:04x}", c as u32).unwrap(),
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+}
@@ -0,0 +1,83 @@
+use libc::{c_char, c_int, c_uint, int32_t, int64_t, uint16_t, uint32_t, uint64_t};
+
+pub const AT_EMPTY_PATH: c_int = 0x1000;
+pub const STATX_DIOALIGN: c_uint = 0x2000;
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug)]
+pub struct statx_timestamp {
+    pub tv_sec: int64_t,
+    pub tv_nsec: uint32_t,
+    pub reserved: int32_t,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug)]
+pub struct statx {
+    pub stx_mask: uint32_t,
+    pub stx_blksize: uint32_t,
+    pub stx_attributes: uint64_t,
+    pub stx_nlink: uint32_t,
+    pub stx_uid: uint32_t,
+    pub stx_gid: uint32_t,
+    pub stx_mode: uint16_t,
+    pub spare0: uint16_t,
+    pub stx_ino: uint64_t,
+    pub stx_size: uint64_t,
+    pub stx_blocks: uint64_t,
+    pub stx_attributes_mask: uint64_t,
+    pub stx_atime: statx_timestamp,
+    pub stx_btime: statx_timestamp,
+    pub stx_ctime: statx_timestamp,
+    pub stx_mtime: statx_timestamp,
+    pub stx_rdev_major: uint32_t,
+    pub stx_rdev_minor: uint32_t,
+    pub stx_dev_major: uint32_t,
+    pub stx_dev_minor: uint32_t,
+    pub stx_mnt_id: uint64_t,
+    pub stx_dio_mem_align: uint32_t,
+    pub stx_dio_offset_align: uint32_t,
+    pub spare3: [uint64_t; 12],
+}
+
+impl statx {
+    pub fn new() -> statx {
+        let timestamp = statx_timestamp {
+            tv_sec: 0,
+            tv_nsec: 0,
+            reserved: 0,
+        };
+        statx {
+            stx_mask: 0,
+            stx_blksize: 0,
+            stx_attributes: 0,
+            stx_nlink: 0,
+            stx_uid: 0,
+            stx_gid: 0,
+            stx_mode: 0,
+            spare0: 0,
+            stx_ino: 0,
+            stx_size: 0,
+            stx_blocks: 0,
+            stx_attributes_mask: 0,
+            stx_atime: timestamp,
+            stx_btime: timestamp,
+            stx_ctime: timestamp,
+            stx_mtime: timestamp,
+            stx_rdev_major: 0,
+            stx_rdev_minor: 0,
+            stx_dev_major: 0,
+            stx_dev_minor: 0,
+            stx_mnt_id: 0,
+            stx_dio_mem_align: 0,
+            stx_dio_offset_align: 0,
+            spare3: [0; 12],
+        }
+    }
+}
+
+extern "C" {
+    pub fn statx(dirfd: c_int, pathname: *const c_char, flags: c_int, mask: c_uint, statxbuf: *mut statx) -> c_int;
+}
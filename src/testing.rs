@@ -0,0 +1,284 @@
+use block::{Operation, Request};
+use input::Input;
+use libc::{self, c_int, c_void};
+use nix;
+use num::cast;
+use sink::Sink;
+use std::cmp;
+use std::collections::VecDeque;
+use std::env;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::process;
+use std::time::Instant;
+
+// Test doubles for the device being rescued and the output, and helpers shared by the tests of
+// several modules.
+
+// A directory of its own for each test, so that they can run in parallel. `name` must be unique
+// across the whole crate.
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("ddarecover-{}-{}", process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Data which does not compress, from a linear congruential generator. Different seeds give
+// different data.
+pub fn noise(seed: u64, length: usize) -> Vec<u8> {
+    let mut state = seed;
+    (0..length).map(|_| {
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (state >> 33) as u8
+    }).collect()
+}
+
+// An input which holds its contents in memory and completes requests in the order they were
+// submitted. Reads overlapping a bad range fail with EIO, as a drive's would, until the range has
+// failed as many reads as it was given, and reads of an unstable range can be scripted to return
+// other data. Every read is recorded, so that what a rescue asked of the device can be inspected
+// exactly.
+#[derive(Debug)]
+pub struct MemoryInput {
+    data: Vec<u8>,
+    sector_size: usize,
+    block_size_physical: usize,
+    max_requests: usize,
+    // Bad ranges and the number of reads each has still to fail
+    bad: Vec<(Range<u64>, usize)>,
+    // Unstable ranges and what each of the next reads of them returns
+    unstable: Vec<(Range<u64>, VecDeque<Vec<u8>>)>,
+    pending: VecDeque<(c_int, Request)>,
+    reads: Vec<Range<u64>>,
+}
+
+impl MemoryInput {
+    pub fn new(data: Vec<u8>, sector_size: usize, block_size_physical: usize) -> MemoryInput {
+        assert!(data.len() % sector_size == 0, "Memory input must be a whole number of sectors");
+        MemoryInput {
+            data: data,
+            sector_size: sector_size,
+            block_size_physical: block_size_physical,
+            max_requests: 8,
+            bad: Vec::new(),
+            unstable: Vec::new(),
+            pending: VecDeque::new(),
+            reads: Vec::new(),
+        }
+    }
+
+    // Makes the next `failures` reads overlapping `range` fail
+    pub fn set_bad(&mut self, range: Range<u64>, failures: usize) {
+        self.bad.push((range, failures));
+    }
+
+    // Makes the next reads overlapping `range` return each of `contents` in turn instead of its
+    // data, as a drive returning different data on each read would. Later reads return the data.
+    pub fn set_unstable(&mut self, range: Range<u64>, contents: Vec<Vec<u8>>) {
+        assert!(contents.iter().all(|c| c.len() as u64 == range.end - range.start));
+        self.unstable.push((range, contents.into_iter().collect()));
+    }
+
+    // Ranges read, in the order the reads were submitted
+    pub fn get_reads(&self) -> &[Range<u64>] {
+        &self.reads
+    }
+
+    // Whether a read of `range` fails, which uses up one failure of each bad range it overlaps
+    fn fail_read(&mut self, range: &Range<u64>) -> bool {
+        let mut failed = false;
+        for &mut (ref bad, ref mut failures) in &mut self.bad {
+            if *failures > 0 && bad.start < range.end && range.start < bad.end {
+                *failures -= 1;
+                failed = true;
+            }
+        }
+        failed
+    }
+
+    fn submit(&mut self, fd: c_int, mut req: Request) -> Result<(), nix::Error> {
+        assert!(self.requests_avail() > 0);
+        req.submitted = Some(Instant::now());
+        req.started = None;
+        req.completed = None;
+        self.pending.push_back((fd, req));
+        Ok(())
+    }
+
+    fn complete_read(&mut self, req: &mut Request) {
+        let range = req.offset..cmp::min(req.offset + req.size, self.data.len() as u64);
+        self.reads.push(range.clone());
+        if self.fail_read(&range) {
+            req.result = -libc::EIO as isize;
+            return;
+        }
+        let length = (range.end - range.start) as usize;
+        req.buffer.as_mut_slice()[..length].copy_from_slice(&self.data[(range.start as usize)..(range.end as usize)]);
+        for &mut (ref unstable, ref mut contents) in &mut self.unstable {
+            if unstable.start >= range.end || range.start >= unstable.end {
+                continue;
+            }
+            if let Some(content) = contents.pop_front() {
+                let overlap = cmp::max(unstable.start, range.start)..cmp::min(unstable.end, range.end);
+                let within_read = (overlap.start - range.start) as usize..(overlap.end - range.start) as usize;
+                let within_content = (overlap.start - unstable.start) as usize..(overlap.end - unstable.start) as usize;
+                req.buffer.as_mut_slice()[within_read].copy_from_slice(&content[within_content]);
+            }
+        }
+        req.result = cast::<usize, isize>(length).unwrap();
+    }
+
+    fn complete_write(fd: c_int, req: &mut Request) {
+        let offset = cast::<u64, libc::off_t>(req.offset).unwrap();
+        let data = req.buffer.as_slice().as_ptr() as *const c_void;
+        let res = unsafe { libc::pwrite(fd, data, req.size as usize, offset) };
+        req.result = if res < 0 {
+            -(io::Error::last_os_error().raw_os_error().unwrap_or(libc::EIO) as isize)
+        } else {
+            res
+        };
+    }
+}
+
+impl Input for MemoryInput {
+    fn submit_request(&mut self, req: Request) -> Result<(), nix::Error> {
+        self.submit(-1, req)
+    }
+
+    fn submit_write(&mut self, fd: c_int, mut req: Request) -> Result<(), nix::Error> {
+        assert!(req.result >= 0, "Cannot write data from a failed read");
+        req.size = cast::<isize, u64>(req.result).unwrap();
+        req.result = -1;
+        req.operation = Operation::Write;
+        self.submit(fd, req)
+    }
+
+    fn get_completed_request(&mut self) -> Result<Request, nix::Error> {
+        let (fd, mut req) = self.pending.pop_front().expect("No requests in flight");
+        match req.operation {
+            Operation::Read => self.complete_read(&mut req),
+            Operation::Write => Self::complete_write(fd, &mut req),
+        }
+        req.started = req.submitted;
+        req.completed = Some(Instant::now());
+        Ok(req)
+    }
+
+    fn last_pending_read(&self) -> Option<u64> {
+        self.pending.iter()
+            .filter(|&&(_, ref req)| req.operation == Operation::Read)
+            .map(|&(_, ref req)| req.offset)
+            .max()
+    }
+
+    fn max_requests(&self) -> usize {
+        self.max_requests
+    }
+
+    fn requests_pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn get_block_size_physical(&self) -> usize {
+        self.block_size_physical
+    }
+
+    fn get_sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn get_size_bytes(&self) -> u64 {
+        self.data.len() as u64
+    }
+}
+
+// A sink which holds everything in memory and records each write, so that the effect of a rescue
+// can be inspected exactly.
+#[derive(Clone, Debug)]
+pub struct MemorySink {
+    data: Vec<u8>,
+    writes: Vec<(u64, Vec<u8>)>,
+    syncs: usize,
+}
+
+impl MemorySink {
+    pub fn new(size_bytes: u64) -> MemorySink {
+        MemorySink {
+            data: vec![0u8; size_bytes as usize],
+            writes: Vec::new(),
+            syncs: 0,
+        }
+    }
+
+    pub fn get_data(&self) -> &[u8] {
+        &self.data
+    }
+
+    // The offset and data of each call to write_at and write_zeros, in the order they were made
+    pub fn get_writes(&self) -> &[(u64, Vec<u8>)] {
+        &self.writes
+    }
+
+    pub fn get_sync_count(&self) -> usize {
+        self.syncs
+    }
+
+    fn check_range(&self, range: &Range<u64>) -> io::Result<()> {
+        if range.start > range.end || range.end > self.data.len() as u64 {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, "Access beyond end of memory sink"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Sink for MemorySink {
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let range = offset..(offset + data.len() as u64);
+        self.check_range(&range)?;
+        self.data[(range.start as usize)..(range.end as usize)].copy_from_slice(data);
+        self.writes.push((offset, data.to_vec()));
+        Ok(())
+    }
+
+    fn write_zeros(&mut self, range: Range<u64>) -> io::Result<()> {
+        self.check_range(&range)?;
+        for value in &mut self.data[(range.start as usize)..(range.end as usize)] {
+            *value = 0;
+        }
+        self.writes.push((range.start, vec![0u8; (range.end - range.start) as usize]));
+        Ok(())
+    }
+
+    fn read_at(&mut self, offset: u64, data: &mut [u8]) -> io::Result<()> {
+        let range = offset..(offset + data.len() as u64);
+        self.check_range(&range)?;
+        data.copy_from_slice(&self.data[(range.start as usize)..(range.end as usize)]);
+        Ok(())
+    }
+
+    fn is_range_zero(&mut self, range: Range<u64>) -> io::Result<bool> {
+        self.check_range(&range)?;
+        Ok(self.data[(range.start as usize)..(range.end as usize)].iter().all(|v| *v == 0))
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.syncs += 1;
+        Ok(())
+    }
+
+    fn get_size_bytes(&self) -> io::Result<u64> {
+        Ok(self.data.len() as u64)
+    }
+
+    fn get_allocated_bytes(&self) -> io::Result<u64> {
+        Ok(self.data.len() as u64)
+    }
+
+    fn is_sparse(&self) -> bool {
+        false
+    }
+}
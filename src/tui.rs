@@ -0,0 +1,251 @@
+use ansi_escapes;
+use libc;
+use map_file::{MapFile, SectorState};
+use status::{self, Status};
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::time::Instant;
+
+// A full-screen status display drawn on the terminal's alternate screen. The device is shown as a
+// grid of cells, each colored by the least advanced state of any region within it, in the manner
+// of ddrescueview. The terminal size is queried on every redraw, so the layout follows any
+// resizing.
+
+const ENTER_ALTERNATE_SCREEN: &'static str = "\x1B[?1049h";
+const LEAVE_ALTERNATE_SCREEN: &'static str = "\x1B[?1049l";
+const RESET: &'static str = "\x1B[0m";
+const POSITION_COLOR: &'static str = "\x1B[97;40m";
+const SPARK_LEVELS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+const MAX_RECENT_ERRORS: usize = 4;
+const MAX_RATE_SAMPLES: usize = 512;
+// Lines above and below the grid
+const HEADER_LINES: usize = 3;
+const FOOTER_LINES: usize = 3 + MAX_RECENT_ERRORS;
+
+#[derive(Debug)]
+pub struct Tui {
+    size: (usize, usize),
+    last_sample: Option<(Instant, u64)>,
+    rate_samples: VecDeque<u64>,
+    recent_errors: VecDeque<(Instant, Range<u64>)>,
+    // Lines for standard error, which would be lost along with the alternate screen
+    held_messages: Vec<String>,
+}
+
+impl Tui {
+    pub fn new() -> io::Result<Tui> {
+        let mut stdout = io::stdout();
+        write!(stdout, "{}{}{}", ENTER_ALTERNATE_SCREEN, ansi_escapes::CursorHide, ansi_escapes::EraseScreen)?;
+        stdout.flush()?;
+        Ok(Tui {
+            size: (0, 0),
+            last_sample: None,
+            rate_samples: VecDeque::new(),
+            recent_errors: VecDeque::new(),
+            held_messages: Vec::new(),
+        })
+    }
+
+    pub fn record_error(&mut self, range: Range<u64>) {
+        if self.recent_errors.len() == MAX_RECENT_ERRORS {
+            self.recent_errors.pop_front();
+        }
+        self.recent_errors.push_back((Instant::now(), range));
+    }
+
+    // Holds back a line for standard error until the display is left
+    pub fn hold_message(&mut self, line: String) {
+        self.held_messages.push(line);
+    }
+
+    pub fn draw(&mut self, status: &Status, map: &MapFile) -> io::Result<()> {
+        self.sample_rate(status);
+        let (columns, rows) = terminal_size();
+        let mut screen = String::new();
+        if (columns, rows) != self.size {
+            screen.push_str(&ansi_escapes::EraseScreen.to_string());
+            self.size = (columns, rows);
+        }
+        screen.push_str(&ansi_escapes::CursorTo::TopLeft.to_string());
+
+        let mut lines = Vec::new();
+        lines.push(truncate(&format!("Phase: {} (pass {})   ipos: {}   run time: {}   remaining: {}",
+                                     status.phase.name(), status.pass, with_percentage(status.pos, status.size),
+                                     status::format_seconds(status.elapsed),
+                                     status::format_seconds(status.remaining_seconds())), columns));
+        lines.push(truncate(&format!("rescued: {}   bad: {}   non-tried: {}   non-trimmed: {}   non-scraped: {}",
+                                     with_percentage(status.rescued, status.size),
+                                     with_percentage(status.bad, status.size),
+                                     status::format_bytes(status.untried),
+                                     status::format_bytes(status.untrimmed),
+                                     status::format_bytes(status.unscraped)), columns));
+        lines.push(legend(columns));
+
+        let grid_rows = rows.saturating_sub(HEADER_LINES + FOOTER_LINES);
+        if grid_rows > 0 && columns > 0 {
+            lines.extend(grid(map, status.pos, columns, grid_rows));
+        }
+
+        let rate_label = format!("read rate: {:>12} ", status::format_rate(status.read_bytes, status.elapsed));
+        let spark_width = columns.saturating_sub(rate_label.chars().count());
+        lines.push(truncate(&format!("{}{}", rate_label, self.sparkline(spark_width)), columns));
+        lines.push(truncate(&format!("Recent errors (last success: {}):", match status.since_success {
+            Some(seconds) => format!("{} ago", status::format_seconds(seconds)),
+            None => String::from("never"),
+        }), columns));
+        let now = Instant::now();
+        for &(time, ref range) in self.recent_errors.iter().rev() {
+            lines.push(truncate(&format!("  0x{:08X}  0x{:08X}  {} ago", range.start, range.end - range.start,
+                                         status::format_seconds(now.duration_since(time).as_secs())), columns));
+        }
+        for _ in self.recent_errors.len()..MAX_RECENT_ERRORS {
+            lines.push(String::new());
+        }
+        lines.push(truncate("Press Ctrl+C to exit.", columns));
+
+        for (idx, line) in lines.iter().take(rows).enumerate() {
+            if idx > 0 {
+                screen.push_str("\r\n");
+            }
+            screen.push_str(line);
+            screen.push_str(&ansi_escapes::EraseEndLine.to_string());
+        }
+        screen.push_str(&ansi_escapes::EraseDown.to_string());
+        let mut stdout = io::stdout();
+        stdout.write_all(screen.as_bytes())?;
+        stdout.flush()
+    }
+
+    fn sample_rate(&mut self, status: &Status) {
+        let now = Instant::now();
+        if let Some((time, read_bytes)) = self.last_sample {
+            let duration = now.duration_since(time);
+            let seconds = duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9;
+            if seconds > 0.0 {
+                if self.rate_samples.len() == MAX_RATE_SAMPLES {
+                    self.rate_samples.pop_front();
+                }
+                self.rate_samples.push_back(((status.read_bytes - read_bytes) as f64 / seconds) as u64);
+            }
+        }
+        self.last_sample = Some((now, status.read_bytes));
+    }
+
+    // The most recent rate samples, scaled to the largest of those shown
+    fn sparkline(&self, width: usize) -> String {
+        let shown: Vec<u64> = self.rate_samples.iter().cloned()
+            .skip(self.rate_samples.len().saturating_sub(width))
+            .collect();
+        let max = cmp::max(shown.iter().cloned().max().unwrap_or(0), 1);
+        shown.iter()
+            .map(|rate| SPARK_LEVELS[(rate * (SPARK_LEVELS.len() as u64 - 1) / max) as usize])
+            .collect()
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        let mut stdout = io::stdout();
+        let _ = write!(stdout, "{}{}{}", RESET, ansi_escapes::CursorShow, LEAVE_ALTERNATE_SCREEN);
+        let _ = stdout.flush();
+        for line in self.held_messages.iter() {
+            eprintln!("{}", line);
+        }
+    }
+}
+
+fn terminal_size() -> (usize, usize) {
+    let mut size: libc::winsize = unsafe { ::std::mem::zeroed() };
+    let result = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+    if result == -1 || size.ws_col == 0 || size.ws_row == 0 {
+        (80, 24)
+    } else {
+        (size.ws_col as usize, size.ws_row as usize)
+    }
+}
+
+// Lower values are drawn in preference to higher ones when a cell contains several states
+fn severity(state: SectorState) -> u8 {
+    match state {
+        SectorState::Bad => 0,
+        SectorState::Unscraped => 1,
+        SectorState::Untrimmed => 2,
+        SectorState::Untried => 3,
+        SectorState::Rescued => 4,
+    }
+}
+
+fn color(state: SectorState) -> &'static str {
+    match state {
+        SectorState::Bad => "\x1B[41m",
+        SectorState::Unscraped => "\x1B[44m",
+        SectorState::Untrimmed => "\x1B[43m",
+        SectorState::Untried => "\x1B[100m",
+        SectorState::Rescued => "\x1B[42m",
+    }
+}
+
+fn legend(columns: usize) -> String {
+    let entries = [
+        (SectorState::Rescued, "rescued"),
+        (SectorState::Untried, "non-tried"),
+        (SectorState::Untrimmed, "non-trimmed"),
+        (SectorState::Unscraped, "non-scraped"),
+        (SectorState::Bad, "bad"),
+    ];
+    let mut result = String::new();
+    let mut width = 0;
+    for &(state, name) in entries.iter() {
+        let entry_width = name.len() + 4;
+        if width + entry_width > columns {
+            break;
+        }
+        result.push_str(&format!("{} {} {}  ", color(state), RESET, name));
+        width += entry_width;
+    }
+    result
+}
+
+fn grid(map: &MapFile, pos: u64, columns: usize, rows: usize) -> Vec<String> {
+    let size = map.get_size();
+    let cells = (columns * rows) as u64;
+    let cell_start = |cell: u64| cell * size / cells;
+    let pos_cell = if size > 0 { cmp::min(pos * cells / size, cells - 1) } else { 0 };
+    let mut lines = Vec::with_capacity(rows);
+    for row in 0..rows {
+        let mut line = String::new();
+        let mut current_color = "";
+        for column in 0..columns {
+            let cell = (row * columns + column) as u64;
+            let range = cell_start(cell)..cell_start(cell + 1);
+            let state = map.iter_range(range)
+                .filter(|r| r.length > 0)
+                .map(|r| r.tag)
+                .min_by_key(|s| severity(*s));
+            let cell_color = match state {
+                _ if cell == pos_cell => POSITION_COLOR,
+                Some(state) => color(state),
+                None => RESET,
+            };
+            if cell_color != current_color {
+                line.push_str(cell_color);
+                current_color = cell_color;
+            }
+            line.push(if cell == pos_cell { '▶' } else { ' ' });
+        }
+        line.push_str(RESET);
+        lines.push(line);
+    }
+    lines
+}
+
+fn with_percentage(bytes: u64, size: u64) -> String {
+    let percentage = if size > 0 { (bytes as f64) * 100.0 / (size as f64) } else { 0.0 };
+    format!("{} ({:.1}%)", status::format_bytes(bytes), percentage)
+}
+
+fn truncate(text: &str, columns: usize) -> String {
+    text.chars().take(columns).collect()
+}
@@ -0,0 +1,73 @@
+use atomic_file;
+use sha256;
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// A record of sectors for which repeated reads returned differing data without error. Each
+// differing sector is logged with the contents returned by both reads, so that the data can be
+// examined or reconstructed later. The log is only created once an unstable sector is found.
+
+pub const SUFFIX: &'static str = "unstable";
+
+#[derive(Debug)]
+pub struct UnstableLog {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl UnstableLog {
+    // The log accompanying the map at `map_path`
+    pub fn for_map(map_path: &Path) -> UnstableLog {
+        UnstableLog {
+            path: atomic_file::generation_path(map_path, SUFFIX),
+            file: None,
+        }
+    }
+
+    // Logs the sectors of a read at `offset` which differ between `first` and `second`, returning
+    // the number of bytes in differing sectors.
+    pub fn record(&mut self, offset: u64, sector_size: usize, first: &[u8], second: &[u8]) -> io::Result<u64> {
+        let length = cmp::max(first.len(), second.len());
+        let mut differing = 0;
+        let mut sector_start = 0;
+        while sector_start < length {
+            let sector_end = cmp::min(sector_start + sector_size, length);
+            let first_sector = &first[cmp::min(sector_start, first.len())..cmp::min(sector_end, first.len())];
+            let second_sector = &second[cmp::min(sector_start, second.len())..cmp::min(sector_end, second.len())];
+            if first_sector != second_sector {
+                let sector_offset = offset + sector_start as u64;
+                let sector_length = (sector_end - sector_start) as u64;
+                let file = self.open()?;
+                writeln!(file, "0x{:08X}  0x{:08X}  first   {}", sector_offset, sector_length, format_contents(first_sector))?;
+                writeln!(file, "0x{:08X}  0x{:08X}  second  {}", sector_offset, sector_length, format_contents(second_sector))?;
+                differing += sector_length;
+            }
+            sector_start = sector_end;
+        }
+        if let Some(ref mut file) = self.file {
+            file.flush()?;
+        }
+        Ok(differing)
+    }
+
+    fn open(&mut self) -> io::Result<&mut File> {
+        if self.file.is_none() {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            self.file = Some(file);
+        }
+        Ok(self.file.as_mut().unwrap())
+    }
+}
+
+fn format_contents(data: &[u8]) -> String {
+    if data.is_empty() {
+        String::from("unread")
+    } else {
+        sha256::to_hex(data)
+    }
+}
@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+
+// Reconstructs data from several differing reads of the same range by taking the most common value
+// of each byte. Only reads of the most common length take part, since a short read says nothing
+// about the bytes it did not return. Ties are resolved in favour of the earliest read.
+pub fn majority_vote(variants: &[Vec<u8>]) -> Vec<u8> {
+    let mut length_counts = HashMap::new();
+    for variant in variants {
+        *length_counts.entry(variant.len()).or_insert(0) += 1;
+    }
+    let length = match variants.iter().map(|v| v.len()).max_by_key(|l| (length_counts[l], *l)) {
+        Some(length) => length,
+        None => return Vec::new(),
+    };
+    let voters: Vec<&Vec<u8>> = variants.iter().filter(|v| v.len() == length).collect();
+
+    let mut result = Vec::with_capacity(length);
+    let mut counts = [0usize; 256];
+    for idx in 0..length {
+        for voter in &voters {
+            counts[voter[idx] as usize] += 1;
+        }
+        let mut best = voters[0][idx];
+        for voter in &voters {
+            if counts[voter[idx] as usize] > counts[best as usize] {
+                best = voter[idx];
+            }
+        }
+        result.push(best);
+        for voter in &voters {
+            counts[voter[idx] as usize] = 0;
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn odd_number_of_reads() {
+        assert_eq!(majority_vote(&[vec![1, 2, 3]]), vec![1, 2, 3]);
+        assert_eq!(majority_vote(&[vec![1, 2, 3], vec![1, 9, 3], vec![1, 2, 3]]), vec![1, 2, 3]);
+        assert_eq!(majority_vote(&[vec![9, 9], vec![1, 2], vec![1, 2], vec![9, 9], vec![1, 2]]), vec![1, 2]);
+    }
+
+    #[test]
+    fn even_number_of_reads() {
+        assert_eq!(majority_vote(&[vec![9, 2], vec![1, 2], vec![1, 2], vec![1, 2]]), vec![1, 2]);
+        assert_eq!(majority_vote(&[vec![7; 4], vec![7; 4], vec![8; 4], vec![7; 4], vec![8; 4], vec![8; 4]]), vec![7; 4]);
+    }
+
+    #[test]
+    fn ties_go_to_the_earliest_read() {
+        assert_eq!(majority_vote(&[vec![1, 2], vec![3, 4]]), vec![1, 2]);
+        assert_eq!(majority_vote(&[vec![3, 4], vec![1, 2]]), vec![3, 4]);
+        // Each byte is decided separately
+        assert_eq!(majority_vote(&[vec![1, 2], vec![5, 6], vec![5, 2], vec![1, 6]]), vec![1, 2]);
+    }
+
+    #[test]
+    fn all_reads_disagree() {
+        assert_eq!(majority_vote(&[vec![1, 1], vec![2, 2], vec![3, 3]]), vec![1, 1]);
+    }
+
+    #[test]
+    fn bytes_are_reconstructed_from_different_reads() {
+        let data: Vec<u8> = (0..64).collect();
+        let variants: Vec<Vec<u8>> = (0..5).map(|i| {
+            let mut variant = data.clone();
+            // Each read is wrong in places where the others are right
+            for idx in (i..data.len()).step_by(5) {
+                variant[idx] ^= 0xFF;
+            }
+            variant
+        }).collect();
+        assert!(variants.iter().all(|v| *v != data));
+        assert_eq!(majority_vote(&variants), data);
+    }
+
+    #[test]
+    fn short_reads_do_not_vote() {
+        assert_eq!(majority_vote(&[vec![5], vec![1, 2], vec![1, 2]]), vec![1, 2]);
+        assert_eq!(majority_vote(&[vec![5, 5, 5], vec![1, 2], vec![1, 2], vec![1, 9]]), vec![1, 2]);
+        assert_eq!(majority_vote(&[]), Vec::<u8>::new());
+    }
+}